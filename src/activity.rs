@@ -0,0 +1,73 @@
+//! Key presses on the main keyboard, for lighting that reacts to typing
+//!
+//! Only key codes are passed on, and only to the LED thread; nothing is
+//! logged.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use evdev::{Device as EvdevDevice, InputEventKind};
+
+use crate::recording;
+
+/// Watches the keyboard's evdev device (interface 0) for key presses
+pub struct KeyListener {
+    stop_flag: Arc<AtomicBool>,
+}
+
+impl KeyListener {
+    /// Start a thread calling `on_key` with the evdev code of each key press
+    pub fn start(on_key: impl Fn(u16) + Send + 'static) -> Result<Self> {
+        let path = recording::find_keyboard_evdev().context("Keyboard evdev device not found")?;
+        let device = EvdevDevice::open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+
+        thread::Builder::new()
+            .name("key-listener".into())
+            .spawn(move || listen_loop(device, on_key, stop_flag_clone))
+            .context("Failed to spawn key listener thread")?;
+
+        log::debug!("Key listener started on {}", path.display());
+        Ok(Self { stop_flag })
+    }
+}
+
+impl Drop for KeyListener {
+    fn drop(&mut self) {
+        // The thread notices on its next event; it holds nothing that needs
+        // releasing, so there's no need to wait for it
+        self.stop_flag.store(true, Ordering::SeqCst);
+    }
+}
+
+fn listen_loop(mut device: EvdevDevice, on_key: impl Fn(u16), stop_flag: Arc<AtomicBool>) {
+    while !stop_flag.load(Ordering::SeqCst) {
+        match device.fetch_events() {
+            Ok(events) => {
+                for event in events {
+                    // 1 = press, 0 = release, 2 = repeat
+                    if let InputEventKind::Key(key) = event.kind() {
+                        if event.value() == 1 {
+                            on_key(key.code());
+                        }
+                    }
+                }
+            }
+            Err(e) if e.raw_os_error() == Some(libc::EAGAIN) => {
+                thread::sleep(Duration::from_millis(10));
+            }
+            Err(e) => {
+                if !stop_flag.load(Ordering::SeqCst) {
+                    log::debug!("Key listener stopped: {}", e);
+                }
+                break;
+            }
+        }
+    }
+    log::debug!("Key listener exited");
+}
@@ -0,0 +1,235 @@
+//! Command-line argument parsing
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+
+use crate::events::G815;
+
+/// Usage text printed for --help
+pub const USAGE: &str = "\
+Usage: gkeys-rs [OPTIONS] [COMMAND]
+
+Commands:
+  run       Run the G-key daemon (default)
+  check     Validate the config file and exit
+  cancel    Cancel the macro running in the daemon
+  reload    Make the daemon re-read its config file
+  indicator <NAME> [VALUE]
+            Set the value of an `ipc` status indicator, or clear it
+
+Options:
+  -c, --config <PATH>      Config file (default: ~/.config/gkeys-rs/config.json)
+  -d, --device <PATH>      hidraw device to use, skipping auto-discovery
+  -p, --profile <PROFILE>  Starting profile (1-3, M1-M3 or MEMORY_1-MEMORY_3)
+  -l, --log-level <LEVEL>  Log filter (error, warn, info, debug, trace), overrides RUST_LOG
+  -n, --dry-run            Log macro actions instead of sending them to uinput
+  -h, --help               Print this help
+  -V, --version            Print version
+";
+
+/// Subcommand to run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Run the daemon
+    Run,
+    /// Load and validate the config, then exit
+    Check,
+    /// Ask the running daemon to cancel its current macro
+    Cancel,
+    /// Ask the running daemon to reload its config
+    Reload,
+    /// Push a value to an `ipc` indicator (None clears it)
+    Indicator { name: String, value: Option<String> },
+    /// Print usage and exit
+    Help,
+    /// Print version and exit
+    Version,
+}
+
+/// Parsed command-line arguments
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cli {
+    pub config: Option<PathBuf>,
+    pub device: Option<PathBuf>,
+    /// Starting profile, normalized to "MEMORY_N"
+    pub profile: Option<String>,
+    pub log_level: Option<String>,
+    pub dry_run: bool,
+    pub command: Command,
+}
+
+impl Cli {
+    /// Parse arguments from the process environment
+    pub fn parse() -> Result<Self> {
+        Self::parse_from(std::env::args().skip(1))
+    }
+
+    /// Parse arguments from an iterator (excluding the program name)
+    pub fn parse_from<I, S>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut cli = Cli {
+            config: None,
+            device: None,
+            profile: None,
+            log_level: None,
+            dry_run: false,
+            command: Command::Run,
+        };
+        let mut operands: Vec<String> = Vec::new();
+        let mut args = args.into_iter().map(Into::into);
+
+        while let Some(arg) = args.next() {
+            // Support both "--opt value" and "--opt=value"
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((f, v)) if f.starts_with("--") => (f.to_string(), Some(v.to_string())),
+                _ => (arg.clone(), None),
+            };
+            let mut value = |name: &str| -> Result<String> {
+                match inline_value.clone() {
+                    Some(v) => Ok(v),
+                    None => args
+                        .next()
+                        .with_context(|| format!("Missing value for {}", name)),
+                }
+            };
+
+            match flag.as_str() {
+                "-c" | "--config" => cli.config = Some(PathBuf::from(value("--config")?)),
+                "-d" | "--device" => cli.device = Some(PathBuf::from(value("--device")?)),
+                "-p" | "--profile" => cli.profile = Some(parse_profile(&value("--profile")?)?),
+                "-l" | "--log-level" => cli.log_level = Some(value("--log-level")?),
+                "-n" | "--dry-run" => cli.dry_run = true,
+                "-h" | "--help" => {
+                    return Ok(Cli {
+                        command: Command::Help,
+                        ..cli
+                    });
+                }
+                "-V" | "--version" => {
+                    return Ok(Cli {
+                        command: Command::Version,
+                        ..cli
+                    });
+                }
+                s if s.starts_with('-') => bail!("Unknown option: {}", s),
+                s => operands.push(s.to_string()),
+            }
+        }
+
+        let Some((command, args)) = operands.split_first() else {
+            return Ok(cli);
+        };
+        cli.command = match command.as_str() {
+            "indicator" => match args {
+                [name] => Command::Indicator {
+                    name: name.clone(),
+                    value: None,
+                },
+                [name, value] => Command::Indicator {
+                    name: name.clone(),
+                    value: Some(value.clone()),
+                },
+                _ => bail!("Usage: gkeys-rs indicator <NAME> [VALUE]"),
+            },
+            s => {
+                if let Some(arg) = args.first() {
+                    bail!("Unexpected argument: {}", arg);
+                }
+                match s {
+                    "run" => Command::Run,
+                    "check" => Command::Check,
+                    "cancel" => Command::Cancel,
+                    "reload" => Command::Reload,
+                    "help" => Command::Help,
+                    _ => bail!("Unknown command: {}", s),
+                }
+            }
+        };
+        Ok(cli)
+    }
+}
+
+/// Normalize a profile argument ("2", "M2", "MEMORY_2") to "MEMORY_2"
+pub fn parse_profile(s: &str) -> Result<String> {
+    let upper = s.trim().to_uppercase();
+    let num = upper
+        .strip_prefix("MEMORY_")
+        .or_else(|| upper.strip_prefix('M'))
+        .unwrap_or(&upper);
+    match num.parse::<u8>() {
+        Ok(n) if (1..=G815.num_mkeys).contains(&n) => Ok(format!("MEMORY_{}", n)),
+        _ => bail!("Invalid profile '{}' (expected 1-{})", s, G815.num_mkeys),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_defaults() {
+        let cli = Cli::parse_from(Vec::<String>::new()).unwrap();
+        assert_eq!(cli.command, Command::Run);
+        assert!(cli.config.is_none());
+        assert!(!cli.dry_run);
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let cli = Cli::parse_from([
+            "--config",
+            "/tmp/gkeys.json",
+            "--device=/dev/hidraw3",
+            "-p",
+            "M2",
+            "--log-level",
+            "debug",
+            "--dry-run",
+            "check",
+        ])
+        .unwrap();
+        assert_eq!(cli.config, Some(PathBuf::from("/tmp/gkeys.json")));
+        assert_eq!(cli.device, Some(PathBuf::from("/dev/hidraw3")));
+        assert_eq!(cli.profile.as_deref(), Some("MEMORY_2"));
+        assert_eq!(cli.log_level.as_deref(), Some("debug"));
+        assert!(cli.dry_run);
+        assert_eq!(cli.command, Command::Check);
+
+        let cli = Cli::parse_from(["cancel"]).unwrap();
+        assert_eq!(cli.command, Command::Cancel);
+
+        let cli = Cli::parse_from(["reload"]).unwrap();
+        assert_eq!(cli.command, Command::Reload);
+
+        let cli = Cli::parse_from(["indicator", "ci", "failed"]).unwrap();
+        assert_eq!(
+            cli.command,
+            Command::Indicator {
+                name: "ci".to_string(),
+                value: Some("failed".to_string())
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Cli::parse_from(["--bogus"]).is_err());
+        assert!(Cli::parse_from(["--config"]).is_err());
+        assert!(Cli::parse_from(["frobnicate"]).is_err());
+        assert!(Cli::parse_from(["reload", "now"]).is_err());
+        assert!(Cli::parse_from(["indicator"]).is_err());
+        assert!(Cli::parse_from(["--profile", "4"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_profile() {
+        assert_eq!(parse_profile("1").unwrap(), "MEMORY_1");
+        assert_eq!(parse_profile("m3").unwrap(), "MEMORY_3");
+        assert_eq!(parse_profile("MEMORY_2").unwrap(), "MEMORY_2");
+        assert!(parse_profile("M0").is_err());
+    }
+}
@@ -0,0 +1,276 @@
+//! Running external commands for macros and notifications
+//!
+//! Every child is waited on, either by the caller or by a reaper thread, so
+//! the daemon never accumulates zombies. Commands run in their own process
+//! group so a timeout also kills anything a shell command started.
+
+use std::io::Read;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+
+use crate::config::RunOptions;
+
+/// How often a running command is checked for exit, timeout or cancellation
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long to keep reading output after a command exits, in case a
+/// process it started in the background holds stdout open
+const OUTPUT_GRACE: Duration = Duration::from_millis(500);
+
+/// Build a command from `argv`, or from a shell command line if `argv` is empty
+pub fn build(shell_command: &str, options: &RunOptions) -> Result<Command> {
+    let mut cmd = match options.argv.split_first() {
+        Some((program, args)) => {
+            let mut cmd = Command::new(program);
+            cmd.args(args);
+            cmd
+        }
+        None => {
+            let mut cmd = Command::new("/bin/sh");
+            cmd.arg("-c").arg(shell_command);
+            cmd
+        }
+    };
+    cmd.envs(&options.env);
+    if let Some(ref cwd) = options.cwd {
+        cmd.current_dir(expand_home(cwd)?);
+    }
+    cmd.stdin(Stdio::null()).process_group(0);
+    Ok(cmd)
+}
+
+/// Expand a leading `~/` to the home directory
+pub fn expand_home(path: &Path) -> Result<PathBuf> {
+    match path.strip_prefix("~") {
+        Ok(rest) => Ok(dirs::home_dir()
+            .context("Could not determine home directory")?
+            .join(rest)),
+        Err(_) => Ok(path.to_path_buf()),
+    }
+}
+
+/// Describe a command for logs without its arguments, which may hold secrets
+pub fn program_name(cmd: &Command) -> String {
+    cmd.get_program().to_string_lossy().into_owned()
+}
+
+/// Spawn a command in the background; a reaper thread waits for it and
+/// kills it after `timeout`
+pub fn spawn_detached(cmd: Command, timeout: Option<Duration>) -> Result<()> {
+    spawn_reaped(cmd, timeout, |_| {})
+}
+
+/// Like [`spawn_detached`], then call `on_exit` from the reaper thread with
+/// whether the command succeeded
+pub fn spawn_reaped(
+    mut cmd: Command,
+    timeout: Option<Duration>,
+    on_exit: impl FnOnce(bool) + Send + 'static,
+) -> Result<()> {
+    let name = program_name(&cmd);
+    let mut child = cmd
+        .stdout(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Failed to start {}", name))?;
+
+    thread::Builder::new()
+        .name("reaper".into())
+        .spawn(move || {
+            // Block in wait() unless there is a timeout to enforce
+            let status = match timeout {
+                Some(_) => wait(child, timeout, || false),
+                None => child.wait().map_err(Into::into),
+            };
+            let success = match status {
+                Ok(status) if !status.success() => {
+                    log::warn!("{} exited with {}", name, describe(status));
+                    false
+                }
+                Ok(_) => {
+                    log::debug!("{} finished", name);
+                    true
+                }
+                Err(e) => {
+                    log::warn!("{}: {:#}", name, e);
+                    false
+                }
+            };
+            on_exit(success);
+        })
+        .context("Failed to spawn reaper thread")?;
+    Ok(())
+}
+
+/// Run a command to completion and capture its stdout
+///
+/// The command is killed after `timeout` or as soon as `cancelled` returns
+/// true; both are reported as errors. Output still open shortly after the
+/// command exits (held by a process it left running) is not waited for.
+pub fn run_captured(
+    mut cmd: Command,
+    timeout: Option<Duration>,
+    cancelled: impl Fn() -> bool,
+) -> Result<(ExitStatus, String)> {
+    let name = program_name(&cmd);
+    let mut child = cmd
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to start {}", name))?;
+
+    // Read on a separate thread so a full pipe can't block the child
+    let mut stdout = child.stdout.take().context("No stdout pipe")?;
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        loop {
+            let chunk = match stdout.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => Ok(buf[..n].to_vec()),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+            let failed = chunk.is_err();
+            if tx.send(chunk).is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let status = wait(child, timeout, &cancelled).with_context(|| name.clone())?;
+
+    let read_until = deadline.map_or(Instant::now() + OUTPUT_GRACE, |d| {
+        d.min(Instant::now() + OUTPUT_GRACE)
+    });
+    let mut output = Vec::new();
+    loop {
+        match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(chunk) => {
+                output.extend(chunk.with_context(|| format!("Failed to read output of {}", name))?)
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+        if cancelled() {
+            return Err(crate::uinput::Cancelled.into());
+        }
+        if Instant::now() >= read_until {
+            log::debug!("{} left a process holding its output open", name);
+            break;
+        }
+    }
+    let output =
+        String::from_utf8(output).with_context(|| format!("{} output is not UTF-8", name))?;
+    Ok((status, output))
+}
+
+/// Wait for a child, killing its process group on timeout or cancellation
+fn wait(
+    mut child: Child,
+    timeout: Option<Duration>,
+    cancelled: impl Fn() -> bool,
+) -> Result<ExitStatus> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if cancelled() {
+            kill(&mut child);
+            return Err(crate::uinput::Cancelled.into());
+        }
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            kill(&mut child);
+            bail!("Timed out after {:?}", timeout.unwrap_or_default());
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Kill a child's whole process group and reap it
+fn kill(child: &mut Child) {
+    // SAFETY: kill has no memory preconditions; the group is the child's
+    // own, as it was started with process_group(0)
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.wait();
+}
+
+/// Human-readable exit status ("code 1", "signal 9")
+pub fn describe(status: ExitStatus) -> String {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("code {}", code),
+        (None, Some(signal)) => format!("signal {}", signal),
+        _ => status.to_string(),
+    }
+}
+
+/// Show a desktop notification via notify-send
+pub fn notify(summary: &str, body: &str) {
+    let mut cmd = Command::new("notify-send");
+    cmd.args(["-a", "gkeys-rs", summary, body])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null());
+    if let Err(e) = spawn_detached(cmd, None) {
+        log::debug!("Notification failed: {:#}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_captured() {
+        let options = RunOptions {
+            argv: vec![
+                "printf".into(),
+                "%s-%s".into(),
+                "a b".into(),
+                "$HOME".into(),
+            ],
+            ..Default::default()
+        };
+        let (status, output) = run_captured(build("", &options).unwrap(), None, || false).unwrap();
+        assert!(status.success());
+        // No shell: arguments are passed through untouched
+        assert_eq!(output, "a b-$HOME");
+
+        let options = RunOptions {
+            env: [("GKEYS_TEST".to_string(), "42".to_string())].into(),
+            cwd: Some(PathBuf::from("/")),
+            ..Default::default()
+        };
+        let cmd = build("echo $GKEYS_TEST; pwd; exit 3", &options).unwrap();
+        let (status, output) = run_captured(cmd, None, || false).unwrap();
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(output, "42\n/\n");
+    }
+
+    #[test]
+    fn test_timeout_kills_process_group() {
+        let cmd = build("sleep 5 & sleep 5", &RunOptions::default()).unwrap();
+        let start = Instant::now();
+        let err = run_captured(cmd, Some(Duration::from_millis(50)), || false).unwrap_err();
+        assert!(format!("{:#}", err).contains("Timed out"));
+        // The background sleep holding stdout open was killed too
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_background_output_not_awaited() {
+        let cmd = build("echo hi; sleep 5 &", &RunOptions::default()).unwrap();
+        let start = Instant::now();
+        let (status, output) = run_captured(cmd, None, || false).unwrap();
+        assert!(status.success());
+        assert_eq!(output, "hi\n");
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
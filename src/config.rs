@@ -1,14 +1,19 @@
 //! Configuration loading and parsing
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Serialize)]
+use crate::effects::Effect;
+use crate::keymap;
+use crate::secret::SecretSource;
+use crate::steps::{self, Action, Step};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     #[serde(default, skip_serializing_if = "String::is_empty")]
     pub keyboard_mapping: String,
@@ -16,6 +21,29 @@ pub struct Config {
     pub notify: StringBool,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub rgb_color: Option<RgbColor>,
+    /// Light G-keys by whether the active profile binds them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gkey_highlight: Option<GKeyHighlight>,
+    #[serde(default, skip_serializing_if = "ClipboardConfig::is_default")]
+    pub clipboard: ClipboardConfig,
+    /// Global typing speed, overridable per macro
+    #[serde(default, skip_serializing_if = "Typing::is_default")]
+    pub typing: Typing,
+    /// Processes that take over the LEDs; effects pause while one is running
+    #[serde(
+        default = "default_external_lighting_tools",
+        skip_serializing_if = "is_default_external_lighting_tools"
+    )]
+    pub external_lighting_tools: Vec<String>,
+    /// Cooperate with a running OpenRGB server for G-key feedback
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub openrgb: Option<OpenRgbConfig>,
+    /// Brightness scale, night-time dimming and blanking when idle
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<Brightness>,
+    /// Keys showing lock key, mic mute or other status
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub indicators: Vec<Indicator>,
     pub profiles: HashMap<String, Profile>,
 }
 
@@ -23,16 +51,18 @@ fn default_notify() -> StringBool {
     StringBool(true)
 }
 
-/// Handle Python-style "True"/"False" strings as bools
-#[derive(Debug, Clone)]
-pub struct StringBool(pub bool);
+fn default_external_lighting_tools() -> Vec<String> {
+    vec!["openrgb".to_string()]
+}
 
-impl Default for StringBool {
-    fn default() -> Self {
-        Self(false)
-    }
+fn is_default_external_lighting_tools(tools: &[String]) -> bool {
+    tools == default_external_lighting_tools()
 }
 
+/// Handle Python-style "True"/"False" strings as bools
+#[derive(Debug, Clone, Default)]
+pub struct StringBool(pub bool);
+
 impl<'de> Deserialize<'de> for StringBool {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -63,53 +93,609 @@ impl Serialize for StringBool {
 }
 
 /// RGB color for keyboard LED configuration
-#[derive(Debug, Clone, Deserialize, Serialize)]
+///
+/// Written as `{"r": 255, "g": 128, "b": 0}` or as a `"#ff8000"` string.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct RgbColor {
     pub r: u8,
     pub g: u8,
     pub b: u8,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl RgbColor {
+    pub fn rgb(&self) -> (u8, u8, u8) {
+        (self.r, self.g, self.b)
+    }
+}
+
+impl<'de> Deserialize<'de> for RgbColor {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum ObjectOrHex {
+            Object { r: u8, g: u8, b: u8 },
+            Hex(String),
+        }
+
+        match ObjectOrHex::deserialize(deserializer)? {
+            ObjectOrHex::Object { r, g, b } => Ok(RgbColor { r, g, b }),
+            ObjectOrHex::Hex(s) => {
+                let hex = s.strip_prefix('#').unwrap_or(&s);
+                match u32::from_str_radix(hex, 16) {
+                    Ok(v) if hex.len() == 6 => Ok(RgbColor {
+                        r: (v >> 16) as u8,
+                        g: (v >> 8) as u8,
+                        b: v as u8,
+                    }),
+                    _ => Err(serde::de::Error::custom(format!(
+                        "invalid color '{}', expected \"#rrggbb\"",
+                        s
+                    ))),
+                }
+            }
+        }
+    }
+}
+
+/// Brightness of every color gkeys-rs sets
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Brightness {
+    /// Percent (0-100)
+    #[serde(default = "default_brightness_level")]
+    pub level: u8,
+    /// Times of day with their own level, e.g. dimmed at night
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedule: Vec<DimPeriod>,
+    /// Turn the lighting off after this many seconds without key presses
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_secs: Option<u64>,
+}
+
+fn default_brightness_level() -> u8 {
+    100
+}
+
+impl Brightness {
+    fn validate(&self) -> Result<()> {
+        let levels = std::iter::once(self.level).chain(self.schedule.iter().map(|p| p.level));
+        for level in levels {
+            if level > 100 {
+                bail!("Brightness level {} is over 100", level);
+            }
+        }
+        if self.idle_secs == Some(0) {
+            bail!("Brightness idle_secs must be at least 1");
+        }
+        Ok(())
+    }
+
+    /// Level at a time of day: the first matching `schedule` period, else `level`
+    pub fn level_at(&self, time: TimeOfDay) -> u8 {
+        self.schedule
+            .iter()
+            .find(|period| period.contains(time))
+            .map_or(self.level, |period| period.level)
+    }
+}
+
+/// A daily period with its own brightness; may wrap past midnight
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DimPeriod {
+    pub from: TimeOfDay,
+    pub to: TimeOfDay,
+    /// Percent (0-100) in place of the global level
+    pub level: u8,
+}
+
+impl DimPeriod {
+    fn contains(&self, time: TimeOfDay) -> bool {
+        if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            time >= self.from || time < self.to
+        }
+    }
+}
+
+/// Time of day in minutes since midnight, written as `"HH:MM"`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeOfDay(pub u16);
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        let parsed = s.split_once(':').and_then(|(h, m)| {
+            let (h, m): (u16, u16) = (h.parse().ok()?, m.parse().ok()?);
+            (h < 24 && m < 60).then_some(TimeOfDay(h * 60 + m))
+        });
+        parsed.ok_or_else(|| {
+            serde::de::Error::custom(format!("invalid time '{}', expected \"HH:MM\"", s))
+        })
+    }
+}
+
+impl Serialize for TimeOfDay {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&format!("{:02}:{:02}", self.0 / 60, self.0 % 60))
+    }
+}
+
+/// A key showing the state of a status source in colors
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Indicator {
+    /// Single key name, usually a G-key or "logo"
+    pub key: String,
+    pub source: IndicatorSource,
+    /// Colors by source value ("on"/"off", or any value pushed over IPC).
+    /// The key shows its usual color for values not listed.
+    pub colors: BTreeMap<String, RgbColor>,
+    /// How often to run `mic_mute` and `command` sources
+    #[serde(default = "default_indicator_interval")]
+    pub interval_secs: u64,
+    /// Compiled LED address of `key`
+    #[serde(skip)]
+    pub address: u8,
+}
+
+fn default_indicator_interval() -> u64 {
+    5
+}
+
+impl Indicator {
+    /// Resolve `key`, filling in `address`
+    fn compile(&mut self) -> Result<()> {
+        self.address = keymap::by_name(&self.key)
+            .with_context(|| format!("Unknown key '{}' in indicator", self.key))?
+            .address;
+        if self.interval_secs == 0 {
+            bail!("Indicator interval_secs must be at least 1");
+        }
+        if self.colors.is_empty() {
+            bail!("Indicator on '{}' has no colors", self.key);
+        }
+        Ok(())
+    }
+}
+
+/// Where an indicator gets its value
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndicatorSource {
+    /// Caps Lock LED state of the keyboard: "on" or "off"
+    CapsLock,
+    /// Num Lock LED state of the keyboard: "on" or "off"
+    NumLock,
+    /// Scroll Lock LED state of the keyboard: "on" or "off"
+    ScrollLock,
+    /// Default microphone muted (via `pactl`): "on" or "off"
+    MicMute,
+    /// Shell command run every `interval_secs`: "on" if it exits 0, else "off"
+    Command(String),
+    /// Value pushed with `gkeys-rs indicator <name> <value>`
+    Ipc(String),
+}
+
+/// G-key colors for bound and unbound keys
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GKeyHighlight {
+    /// Color of G-keys with a macro
+    pub bound: RgbColor,
+    /// Color of G-keys without a macro, or with a `nothing` macro (default off)
+    #[serde(default = "default_unbound")]
+    pub unbound: RgbColor,
+}
+
+fn default_unbound() -> RgbColor {
+    RgbColor { r: 0, g: 0, b: 0 }
+}
+
+/// Per-profile key colors
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Lighting {
+    /// Colors by key name ("esc", "g1", "kp_enter") or group ("gkeys", "all")
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub keys: HashMap<String, RgbColor>,
+    /// Compiled (LED address, color) pairs, sorted by address
+    #[serde(skip)]
+    pub key_colors: Vec<(u8, (u8, u8, u8))>,
+    /// Animated effect drawn over `keys` (except the G-keys)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effect: Option<Effect>,
+    /// Effect frames per second
+    #[serde(default = "default_fps", skip_serializing_if = "is_default_fps")]
+    pub fps: u32,
+}
+
+fn default_fps() -> u32 {
+    30
+}
+
+fn is_default_fps(fps: &u32) -> bool {
+    *fps == default_fps()
+}
+
+/// Highest effect frame rate; each frame takes several HID reports
+const MAX_FPS: u32 = 60;
+
+impl Lighting {
+    /// Resolve key and group names, filling in `key_colors`
+    ///
+    /// Larger groups are applied first, so a single key overrides its group
+    /// and a group overrides "all".
+    pub fn compile(&mut self) -> Result<()> {
+        let mut entries = Vec::new();
+        for (name, color) in &self.keys {
+            let addresses = keymap::addresses(name)
+                .with_context(|| format!("Unknown key or group '{}' in lighting", name))?;
+            entries.push((addresses, color.rgb()));
+        }
+        entries.sort_by_key(|(addresses, _)| std::cmp::Reverse(addresses.len()));
+
+        let mut colors = BTreeMap::new();
+        for (addresses, color) in entries {
+            for address in addresses {
+                colors.insert(address, color);
+            }
+        }
+        self.key_colors = colors.into_iter().collect();
+
+        if !(1..=MAX_FPS).contains(&self.fps) {
+            bail!("Lighting fps must be between 1 and {}", MAX_FPS);
+        }
+        Ok(())
+    }
+}
+
+/// OpenRGB SDK server used for G-key feedback
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OpenRgbConfig {
+    #[serde(default = "default_openrgb_host")]
+    pub host: String,
+    #[serde(default = "default_openrgb_port")]
+    pub port: u16,
+    /// Part of the controller name to use; defaults to the first one with G-keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    #[serde(default)]
+    pub feedback: OpenRgbFeedback,
+}
+
+fn default_openrgb_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_openrgb_port() -> u16 {
+    6742
+}
+
+/// How G-key feedback works alongside OpenRGB
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OpenRgbFeedback {
+    /// Read the G-key colors from OpenRGB before feedback and put them back after
+    #[default]
+    Restore,
+    /// Also set the G-keys through OpenRGB instead of writing to the keyboard
+    Send,
+}
+
+/// Clipboard helper used by the `clipboard` typeout fallback
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ClipboardConfig {
+    /// Command that reads text on stdin and places it on the clipboard
+    #[serde(default = "default_clipboard_command")]
+    pub command: String,
+    /// Shortcut sent to paste the clipboard contents
+    #[serde(default = "default_paste_shortcut")]
+    pub paste: String,
+    /// Compiled `paste` shortcut
+    #[serde(skip)]
+    pub paste_actions: Vec<Action>,
+}
+
+fn default_clipboard_command() -> String {
+    "wl-copy".to_string()
+}
+
+fn default_paste_shortcut() -> String {
+    "ctrl+v".to_string()
+}
+
+impl Default for ClipboardConfig {
+    fn default() -> Self {
+        Self {
+            command: default_clipboard_command(),
+            paste: default_paste_shortcut(),
+            paste_actions: vec![Action::Chord(vec![
+                crate::uinput::keys::KEY_LEFTCTRL,
+                crate::uinput::keys::KEY_V,
+            ])],
+        }
+    }
+}
+
+impl ClipboardConfig {
+    fn is_default(&self) -> bool {
+        self.command == default_clipboard_command() && self.paste == default_paste_shortcut()
+    }
+
+    /// Check the `paste` shortcut, filling in `paste_actions`
+    fn compile(&mut self) -> Result<()> {
+        if self.paste.trim().is_empty() {
+            bail!("Empty clipboard paste shortcut");
+        }
+        self.paste_actions = steps::compile_shortcut(&self.paste).context("clipboard paste")?;
+        Ok(())
+    }
+}
+
+/// Typing speed settings; unset fields inherit from the global settings,
+/// then the built-in defaults
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Typing {
+    /// How long each key is held down (ms)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hold_ms: Option<u64>,
+    /// Delay between typed characters (ms)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_delay_ms: Option<u64>,
+    /// Delay between chords of a sequence (ms)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chord_delay_ms: Option<u64>,
+    /// Maximum random extra delay added to each delay (ms)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jitter_ms: Option<u64>,
+}
+
+impl Typing {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Fill fields not set here from `base`
+    pub fn or(&self, base: &Typing) -> Typing {
+        Typing {
+            hold_ms: self.hold_ms.or(base.hold_ms),
+            key_delay_ms: self.key_delay_ms.or(base.key_delay_ms),
+            chord_delay_ms: self.chord_delay_ms.or(base.chord_delay_ms),
+            jitter_ms: self.jitter_ms.or(base.jitter_ms),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Profile {
+    /// Key colors applied while this profile is active
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lighting: Option<Lighting>,
     #[serde(flatten)]
     pub macros: HashMap<String, Macro>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Macro {
     pub hotkey_type: HotkeyType,
     #[serde(rename = "do", default, skip_serializing_if = "String::is_empty")]
     pub action: String,
+    /// How typeout handles characters missing from the keyboard layout
+    #[serde(default, skip_serializing_if = "Fallback::is_skip")]
+    pub fallback: Fallback,
+    /// Typing speed overrides for this macro
+    #[serde(default, skip_serializing_if = "Typing::is_default")]
+    pub typing: Typing,
+    /// What to do when the G-key is pressed again while this macro runs
+    #[serde(default, skip_serializing_if = "RepeatPolicy::is_queue")]
+    pub on_repeat: RepeatPolicy,
+    /// Step list for `steps` macros
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<Step>,
+    /// Process options for `run` and `typecmd` macros
+    #[serde(flatten)]
+    pub run: RunOptions,
+    /// Where a secret `typeout` macro reads its text, instead of `do`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<SecretSource>,
+    /// Pulse the G-key while the macro runs, then flash green or red
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub led_feedback: bool,
+    /// Compiled key actions for shortcut, sequence, uinput and steps macros
+    #[serde(skip)]
+    pub actions: Vec<Action>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+impl Macro {
+    /// Whether the text this macro types must be kept out of logs and recordings
+    pub fn is_secret(&self) -> bool {
+        self.secret.is_some() || self.hotkey_type == HotkeyType::Typecmd
+    }
+
+    /// Parse and validate key names, filling in `actions`
+    pub fn compile(&mut self) -> Result<()> {
+        if self.secret.is_some() {
+            if self.hotkey_type != HotkeyType::Typeout {
+                bail!("`secret` is only supported on typeout macros");
+            }
+            if !self.action.is_empty() {
+                bail!("Secret macro must not have `do`; the text comes from `secret`");
+            }
+            if !self.run.argv.is_empty() {
+                bail!("Secret macro must not have `argv`; use a `command` secret");
+            }
+            // The clipboard helper and clipboard managers would keep the text
+            if self.fallback == Fallback::Clipboard {
+                bail!("Secret macro can't use the clipboard fallback");
+            }
+        }
+        self.actions = match self.hotkey_type {
+            HotkeyType::Shortcut => steps::compile_shortcut(&self.action)?,
+            HotkeyType::Sequence => steps::compile_sequence(&self.action)?,
+            HotkeyType::Uinput => steps::compile_uinput(&self.action)?,
+            HotkeyType::Steps => steps::compile(&self.steps)?,
+            HotkeyType::Run | HotkeyType::Typecmd => {
+                match (self.action.trim().is_empty(), self.run.argv.is_empty()) {
+                    (true, true) => bail!("Command macro needs a command in `do` or `argv`"),
+                    (false, false) => bail!("Command macro has both `do` and `argv`; use one"),
+                    _ => {}
+                }
+                Vec::new()
+            }
+            HotkeyType::Typeout | HotkeyType::Nothing => Vec::new(),
+        };
+        Ok(())
+    }
+}
+
+/// Process options for `run` and `typecmd` macros
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RunOptions {
+    /// Program and arguments, run directly without a shell (instead of `do`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub argv: Vec<String>,
+    /// Extra environment variables
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    /// Working directory (`~/` is expanded)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    /// Kill the command if it runs longer than this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// What to do with the command's stdout
+    #[serde(default, skip_serializing_if = "OutputAction::is_discard")]
+    pub output: OutputAction,
+}
+
+/// What to do with the stdout of a `run` macro
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputAction {
+    /// Ignore it; the command runs in the background
+    #[default]
+    Discard,
+    /// Type it out with the virtual keyboard
+    Type,
+    /// Show it in a desktop notification
+    Notify,
+    /// Write it to the log
+    Log,
+}
+
+impl OutputAction {
+    fn is_discard(&self) -> bool {
+        *self == OutputAction::Discard
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum HotkeyType {
     Run,
     Shortcut,
     Typeout,
+    /// Type the output of a command
+    Typecmd,
     Uinput,
     Sequence,
+    Steps,
+    #[default]
     Nothing,
 }
 
-impl Config {
-    /// Load config from the default location
-    pub fn load() -> Result<Self> {
-        let path = Self::config_path()?;
-        Self::load_from(&path)
+/// Behaviour when a macro's G-key is pressed while it is running or queued
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RepeatPolicy {
+    /// Run it again after the current run finishes
+    #[default]
+    Queue,
+    /// Drop the new press
+    Ignore,
+    /// Cancel the current run and start again
+    Restart,
+    /// Cancel the current run without starting again
+    Cancel,
+}
+
+impl RepeatPolicy {
+    fn is_queue(&self) -> bool {
+        *self == RepeatPolicy::Queue
     }
+}
 
+/// Strategy for typing characters the keyboard layout cannot produce
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Fallback {
+    /// Skip the character (logged)
+    #[default]
+    Skip,
+    /// GTK/IBus Unicode entry: ctrl+shift+u, hex code point, space
+    Unicode,
+    /// Compose key sequence (requires a Compose key mapped to KEY_COMPOSE)
+    Compose,
+    /// Copy via the clipboard helper command, then paste
+    Clipboard,
+}
+
+impl Fallback {
+    fn is_skip(&self) -> bool {
+        *self == Fallback::Skip
+    }
+}
+
+impl Config {
     /// Load config from a specific path
-    pub fn load_from(path: &PathBuf) -> Result<Self> {
+    pub fn load_from(path: &Path) -> Result<Self> {
         let content = fs::read_to_string(path)
             .with_context(|| format!("Failed to read config from {}", path.display()))?;
-        let config: Config = serde_json::from_str(&content)
+        let mut config: Config = serde_json::from_str(&content)
             .with_context(|| format!("Failed to parse config from {}", path.display()))?;
+        config
+            .compile()
+            .with_context(|| format!("Invalid config in {}", path.display()))?;
         Ok(config)
     }
 
+    /// Compile and validate every macro
+    fn compile(&mut self) -> Result<()> {
+        self.clipboard.compile()?;
+        if let Some(ref brightness) = self.brightness {
+            brightness.validate()?;
+        }
+        let mut indicator_keys = Vec::new();
+        for indicator in &mut self.indicators {
+            indicator.compile()?;
+            if indicator_keys.contains(&indicator.address) {
+                bail!("More than one indicator on '{}'", indicator.key);
+            }
+            indicator_keys.push(indicator.address);
+        }
+        for (profile_name, profile) in &mut self.profiles {
+            if let Some(ref mut lighting) = profile.lighting {
+                lighting.compile().context(profile_name.clone())?;
+            }
+            for (macro_name, macro_def) in &mut profile.macros {
+                macro_def
+                    .compile()
+                    .with_context(|| format!("{} {}", profile_name, macro_name))?;
+            }
+        }
+        Ok(())
+    }
+
     /// Get the default config path
     pub fn config_path() -> Result<PathBuf> {
         let config_dir = dirs::config_dir()
@@ -123,25 +709,75 @@ impl Config {
         self.profiles.get(profile)?.macros.get(macro_name)
     }
 
-    /// Set a macro definition for the given profile and key
-    pub fn set_macro(&mut self, profile: &str, macro_name: &str, macro_def: Macro) {
+    /// Get the lighting for a profile, if it defines any
+    pub fn get_lighting(&self, profile: &str) -> Option<&Lighting> {
+        self.profiles.get(profile)?.lighting.as_ref()
+    }
+
+    /// Brightness settings to apply. Idle blanking is left out unless key
+    /// presses can be seen, so typing can't blank the lighting.
+    pub fn effective_brightness(&self, sees_key_presses: bool) -> Option<Brightness> {
+        let mut brightness = self.brightness.clone()?;
+        if !sees_key_presses {
+            brightness.idle_secs = None;
+        }
+        Some(brightness)
+    }
+
+    /// Whether the LED thread needs key presses: for idle blanking, or
+    /// because a profile has an effect that reacts to them
+    pub fn wants_key_presses(&self) -> bool {
+        let idle = self
+            .brightness
+            .as_ref()
+            .is_some_and(|b| b.idle_secs.is_some());
+        idle || self
+            .profiles
+            .values()
+            .filter_map(|profile| profile.lighting.as_ref()?.effect.as_ref())
+            .any(Effect::is_reactive)
+    }
+
+    /// G-key colors (G1-G5) showing which keys the profile binds, if
+    /// `gkey_highlight` is enabled
+    pub fn gkey_highlight_colors(&self, profile: &str) -> Option<[(u8, u8, u8); 5]> {
+        let highlight = self.gkey_highlight.as_ref()?;
+        Some(std::array::from_fn(|i| {
+            match self.get_macro(profile, &format!("MACRO_{}", i + 1)) {
+                Some(m) if m.hotkey_type != HotkeyType::Nothing => highlight.bound.rgb(),
+                _ => highlight.unbound.rgb(),
+            }
+        }))
+    }
+
+    /// Validate and set a macro definition for the given profile and key
+    pub fn set_macro(
+        &mut self,
+        profile: &str,
+        macro_name: &str,
+        mut macro_def: Macro,
+    ) -> Result<()> {
+        macro_def
+            .compile()
+            .with_context(|| format!("{} {}", profile, macro_name))?;
         let profile_entry = self
             .profiles
             .entry(profile.to_string())
             .or_insert_with(|| Profile {
+                lighting: None,
                 macros: HashMap::new(),
             });
         profile_entry.macros.insert(macro_name.to_string(), macro_def);
+        Ok(())
     }
 
-    /// Save config to file, creating a backup first
-    pub fn save(&self) -> Result<()> {
-        let path = Self::config_path()?;
+    /// Save config to the given file, creating a backup first
+    pub fn save_to(&self, path: &Path) -> Result<()> {
         let backup_path = path.with_extension("json.bak");
 
         // Create backup if file exists
         if path.exists() {
-            fs::copy(&path, &backup_path)
+            fs::copy(path, &backup_path)
                 .with_context(|| format!("Failed to create backup at {}", backup_path.display()))?;
             log::info!("Created config backup at {}", backup_path.display());
         }
@@ -149,7 +785,7 @@ impl Config {
         // Write new config with pretty formatting
         let json = serde_json::to_string_pretty(self)
             .context("Failed to serialize config")?;
-        let mut file = fs::File::create(&path)
+        let mut file = fs::File::create(path)
             .with_context(|| format!("Failed to create config at {}", path.display()))?;
         file.write_all(json.as_bytes())
             .with_context(|| format!("Failed to write config to {}", path.display()))?;
@@ -182,5 +818,281 @@ mod tests {
         let m1 = config.get_macro("MEMORY_1", "MACRO_1").unwrap();
         assert_eq!(m1.hotkey_type, HotkeyType::Run);
         assert_eq!(m1.action, "echo hello");
+        assert_eq!(m1.fallback, Fallback::Skip);
+        assert_eq!(config.clipboard, ClipboardConfig::default());
+    }
+
+    #[test]
+    fn test_parse_fallback() {
+        let json = r#"{
+            "clipboard": { "paste": "ctrl+shift+v" },
+            "profiles": {
+                "MEMORY_1": {
+                    "MACRO_1": { "hotkey_type": "typeout", "do": "café", "fallback": "unicode" }
+                }
+            }
+        }"#;
+
+        let mut config: Config = serde_json::from_str(json).unwrap();
+        config.compile().unwrap();
+        assert_eq!(config.clipboard.command, "wl-copy");
+        assert_eq!(config.clipboard.paste, "ctrl+shift+v");
+        assert_eq!(
+            config.clipboard.paste_actions,
+            vec![Action::Chord(vec![
+                crate::uinput::keys::KEY_LEFTCTRL,
+                crate::uinput::keys::KEY_LEFTSHIFT,
+                crate::uinput::keys::KEY_V
+            ])]
+        );
+        config.clipboard.paste = "ctrl+nosuchkey".to_string();
+        assert!(config.compile().is_err());
+        let m1 = config.get_macro("MEMORY_1", "MACRO_1").unwrap();
+        assert_eq!(m1.fallback, Fallback::Unicode);
+    }
+
+    #[test]
+    fn test_compile_macros() {
+        let json = r#"{
+            "profiles": {
+                "MEMORY_1": {
+                    "MACRO_1": { "hotkey_type": "sequence", "do": "ctrl+a, ctrl+c" },
+                    "MACRO_2": { "hotkey_type": "steps", "steps": [ { "tap": "enter" } ] }
+                }
+            }
+        }"#;
+
+        let mut config: Config = serde_json::from_str(json).unwrap();
+        config.compile().unwrap();
+        let actions = |name| &config.get_macro("MEMORY_1", name).unwrap().actions;
+        assert_eq!(actions("MACRO_1").len(), 2);
+        assert_eq!(actions("MACRO_2").len(), 1);
+
+        let bad = json.replace("ctrl+c", "ctrl+nosuchkey");
+        let mut config: Config = serde_json::from_str(&bad).unwrap();
+        let err = config.compile().unwrap_err();
+        assert!(format!("{:#}", err).contains("MEMORY_1 MACRO_1"));
+    }
+
+    #[test]
+    fn test_run_options() {
+        let json = r#"{
+            "hotkey_type": "run",
+            "argv": ["date", "+%F"],
+            "env": { "TZ": "UTC" },
+            "cwd": "~/",
+            "timeout_ms": 2000,
+            "output": "type"
+        }"#;
+        let mut m: Macro = serde_json::from_str(json).unwrap();
+        m.compile().unwrap();
+        assert_eq!(m.run.argv, ["date", "+%F"]);
+        assert_eq!(m.run.env.get("TZ").map(String::as_str), Some("UTC"));
+        assert_eq!(m.run.timeout_ms, Some(2000));
+        assert_eq!(m.run.output, OutputAction::Type);
+
+        m.action = "date".into();
+        assert!(m.compile().is_err());
+        m.run.argv.clear();
+        m.compile().unwrap();
+        m.action.clear();
+        assert!(m.compile().is_err());
+    }
+
+    #[test]
+    fn test_secret_macro() {
+        let mut m: Macro = serde_json::from_str(
+            r#"{ "hotkey_type": "typeout", "secret": { "file": "~/.config/gkeys-rs/pin" } }"#,
+        )
+        .unwrap();
+        m.compile().unwrap();
+        assert!(m.is_secret());
+        assert_eq!(
+            m.secret,
+            Some(SecretSource::File(PathBuf::from("~/.config/gkeys-rs/pin")))
+        );
+
+        m.action = "1234".into();
+        assert!(m.compile().is_err());
+        m.action.clear();
+        m.fallback = Fallback::Clipboard;
+        assert!(m.compile().is_err());
+        m.fallback = Fallback::default();
+        m.hotkey_type = HotkeyType::Run;
+        assert!(m.compile().is_err());
+    }
+
+    #[test]
+    fn test_typing_override() {
+        let json = r#"{
+            "typing": { "key_delay_ms": 30, "jitter_ms": 5 },
+            "profiles": {
+                "MEMORY_1": {
+                    "MACRO_1": { "hotkey_type": "typeout", "do": "x", "typing": { "key_delay_ms": 2, "hold_ms": 1 } }
+                }
+            }
+        }"#;
+
+        let config: Config = serde_json::from_str(json).unwrap();
+        let m1 = config.get_macro("MEMORY_1", "MACRO_1").unwrap();
+        let typing = m1.typing.or(&config.typing);
+        assert_eq!(typing.hold_ms, Some(1));
+        assert_eq!(typing.key_delay_ms, Some(2));
+        assert_eq!(typing.chord_delay_ms, None);
+        assert_eq!(typing.jitter_ms, Some(5));
+    }
+
+    #[test]
+    fn test_profile_lighting() {
+        let json = r##"{
+            "rgb_color": "#102030",
+            "profiles": {
+                "MEMORY_1": {
+                    "lighting": {
+                        "keys": {
+                            "all": { "r": 0, "g": 0, "b": 32 },
+                            "gkeys": "#ff8000",
+                            "g2": "#00ff00",
+                            "kp_enter": "FF0000"
+                        },
+                        "effect": { "reactive": { "color": "#ffffff" } }
+                    },
+                    "MACRO_1": { "hotkey_type": "nothing" }
+                },
+                "MEMORY_2": {}
+            }
+        }"##;
+        let mut config: Config = serde_json::from_str(json).unwrap();
+        config.compile().unwrap();
+        assert_eq!(config.rgb_color.as_ref().unwrap().rgb(), (0x10, 0x20, 0x30));
+        assert!(config.get_macro("MEMORY_1", "MACRO_1").is_some());
+        assert!(config.get_lighting("MEMORY_2").is_none());
+
+        let colors = &config.get_lighting("MEMORY_1").unwrap().key_colors;
+        assert_eq!(colors.len(), keymap::KEYS.len());
+        let color = |name| {
+            let address = keymap::by_name(name).unwrap().address;
+            colors.iter().find(|&&(a, _)| a == address).unwrap().1
+        };
+        assert_eq!(color("esc"), (0, 0, 32));
+        assert_eq!(color("g1"), (255, 128, 0));
+        assert_eq!(color("g2"), (0, 255, 0));
+        assert_eq!(color("kp_enter"), (255, 0, 0));
+        assert_eq!(config.get_lighting("MEMORY_1").unwrap().fps, 30);
+        assert!(config.wants_key_presses());
+        assert_eq!(config.external_lighting_tools, ["openrgb"]);
+        // Defaults aren't written back
+        let saved = serde_json::to_string(&config).unwrap();
+        assert!(!saved.contains("external_lighting_tools"), "{}", saved);
+        assert!(!saved.contains("fps"), "{}", saved);
+
+        let bad =
+            r##"{ "profiles": { "MEMORY_1": { "lighting": { "keys": { "g9": "#000000" } } } } }"##;
+        let mut config: Config = serde_json::from_str(bad).unwrap();
+        assert!(config.compile().is_err());
+        let bad = r#"{ "profiles": { "MEMORY_1": { "lighting": { "fps": 0 } } } }"#;
+        let mut config: Config = serde_json::from_str(bad).unwrap();
+        assert!(config.compile().is_err());
+        assert!(serde_json::from_str::<RgbColor>(r##""#12345""##).is_err());
+    }
+
+    #[test]
+    fn test_brightness() {
+        let json = r#"{
+            "brightness": {
+                "level": 80,
+                "schedule": [
+                    { "from": "22:30", "to": "07:00", "level": 10 },
+                    { "from": "12:00", "to": "13:00", "level": 0 }
+                ],
+                "idle_secs": 300
+            },
+            "profiles": {}
+        }"#;
+        let mut config: Config = serde_json::from_str(json).unwrap();
+        config.compile().unwrap();
+        assert!(config.wants_key_presses());
+        let brightness = config.brightness.as_ref().unwrap();
+        let at = |h: u16, m: u16| brightness.level_at(TimeOfDay(h * 60 + m));
+        assert_eq!(at(22, 29), 80);
+        assert_eq!(at(22, 30), 10);
+        assert_eq!(at(3, 0), 10);
+        assert_eq!(at(7, 0), 80);
+        assert_eq!(at(12, 59), 0);
+        let saved = serde_json::to_string(brightness).unwrap();
+        assert!(saved.contains(r#""from":"22:30""#), "{}", saved);
+        let seen = config.effective_brightness(true).unwrap();
+        assert_eq!(seen.idle_secs, Some(300));
+        // No idle blanking without the key press listener; the rest stays
+        let unseen = config.effective_brightness(false).unwrap();
+        assert_eq!(unseen.idle_secs, None);
+        assert_eq!(unseen.level, 80);
+
+        let bad = r#"{ "brightness": { "schedule": [{ "from": "24:00", "to": "07:00", "level": 0 }] }, "profiles": {} }"#;
+        assert!(serde_json::from_str::<Config>(bad).is_err());
+        let bad = r#"{ "brightness": { "level": 101 }, "profiles": {} }"#;
+        let mut config: Config = serde_json::from_str(bad).unwrap();
+        assert!(config.compile().is_err());
+    }
+
+    #[test]
+    fn test_indicators() {
+        let json = r##"{
+            "indicators": [
+                { "key": "g5", "source": "caps_lock", "colors": { "on": "#ff0000" } },
+                {
+                    "key": "logo",
+                    "source": { "command": "ping -c1 vpn.example" },
+                    "colors": { "on": "#00ff00", "off": "#ff0000" },
+                    "interval_secs": 30
+                },
+                { "key": "G4", "source": { "ipc": "ci" }, "colors": { "failed": "#ff0000" } }
+            ],
+            "profiles": {}
+        }"##;
+        let mut config: Config = serde_json::from_str(json).unwrap();
+        config.compile().unwrap();
+        let indicators = &config.indicators;
+        assert_eq!(indicators[0].source, IndicatorSource::CapsLock);
+        assert_eq!(indicators[0].address, keymap::gkey_address(5));
+        assert_eq!(indicators[0].interval_secs, 5);
+        assert_eq!(
+            indicators[1].source,
+            IndicatorSource::Command("ping -c1 vpn.example".to_string())
+        );
+        assert_eq!(indicators[2].address, keymap::gkey_address(4));
+
+        let bad = r##"{ "indicators": [{ "key": "gkeys", "source": "num_lock", "colors": { "on": "#ffffff" } }], "profiles": {} }"##;
+        let mut config: Config = serde_json::from_str(bad).unwrap();
+        assert!(config.compile().is_err());
+        let bad = r##"{ "indicators": [
+            { "key": "g1", "source": "num_lock", "colors": { "on": "#ffffff" } },
+            { "key": "g1", "source": "mic_mute", "colors": { "on": "#ffffff" } }
+        ], "profiles": {} }"##;
+        let mut config: Config = serde_json::from_str(bad).unwrap();
+        assert!(config.compile().is_err());
+    }
+
+    #[test]
+    fn test_gkey_highlight() {
+        let json = r##"{
+            "gkey_highlight": { "bound": "#00ff00" },
+            "profiles": {
+                "MEMORY_1": {
+                    "MACRO_1": { "hotkey_type": "run", "do": "true" },
+                    "MACRO_3": { "hotkey_type": "nothing" }
+                }
+            }
+        }"##;
+        let config: Config = serde_json::from_str(json).unwrap();
+        let (on, off) = ((0, 255, 0), (0, 0, 0));
+        assert_eq!(
+            config.gkey_highlight_colors("MEMORY_1"),
+            Some([on, off, off, off, off])
+        );
+        assert_eq!(config.gkey_highlight_colors("MEMORY_2"), Some([off; 5]));
+
+        let config: Config = serde_json::from_str(r#"{ "profiles": {} }"#).unwrap();
+        assert_eq!(config.gkey_highlight_colors("MEMORY_1"), None);
     }
 }
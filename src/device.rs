@@ -16,9 +16,14 @@ pub struct Device {
 }
 
 impl Device {
-    /// Open the G815 keyboard hidraw device
+    /// Find and open the G815 keyboard hidraw device
     pub fn open() -> Result<Self> {
         let path = find_hidraw_device()?;
+        Self::open_path(path)
+    }
+
+    /// Open a specific hidraw device, skipping auto-discovery
+    pub fn open_path(path: PathBuf) -> Result<Self> {
         // Open with read+write for both receiving events and sending commands
         let file = OpenOptions::new()
             .read(true)
@@ -102,16 +107,6 @@ impl Device {
         self.read_event_timeout(Duration::from_millis(100))
     }
 
-    /// Read and parse a HID event (blocking)
-    pub fn read_event_blocking(&mut self) -> Result<Option<Event>> {
-        let mut buf = [0u8; 20];
-        match self.file.read(&mut buf) {
-            Ok(n) if n > 0 => Ok(parse_report(&buf[..n])),
-            Ok(_) => Ok(None),
-            Err(e) => Err(e.into()),
-        }
-    }
-
     /// Read and parse a HID event with specified timeout
     pub fn read_event_timeout(&mut self, timeout: Duration) -> Result<Option<Event>> {
         let fd = self.file.as_raw_fd();
@@ -0,0 +1,280 @@
+//! Animated lighting effects
+//!
+//! The LED thread renders a frame of the active profile's effect at the
+//! configured frame rate. Effects cover every key except the G-keys, which
+//! keep showing macro state. While an external lighting tool such as OpenRGB
+//! is running, effects pause so the two don't fight over the LEDs.
+
+use std::collections::HashMap;
+use std::f32::consts::TAU;
+use std::fs;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::RgbColor;
+use crate::keymap::{self, LedKey};
+
+/// An animated lighting effect
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Effect {
+    /// Fade one color in and out
+    Breathing {
+        color: RgbColor,
+        #[serde(default = "default_breathing_period")]
+        period_ms: u64,
+    },
+    /// Move the whole keyboard through the rainbow
+    Cycle {
+        #[serde(default = "default_cycle_period")]
+        period_ms: u64,
+    },
+    /// Sweep a rainbow across the keyboard from left to right
+    Wave {
+        #[serde(default = "default_wave_period")]
+        period_ms: u64,
+    },
+    /// Light pressed keys and fade them back to their profile color
+    Reactive {
+        color: RgbColor,
+        #[serde(default = "default_fade")]
+        fade_ms: u64,
+    },
+}
+
+fn default_breathing_period() -> u64 {
+    4000
+}
+
+fn default_cycle_period() -> u64 {
+    10000
+}
+
+fn default_wave_period() -> u64 {
+    3000
+}
+
+fn default_fade() -> u64 {
+    500
+}
+
+impl Effect {
+    /// Whether the effect needs key presses from the keyboard
+    pub fn is_reactive(&self) -> bool {
+        matches!(self, Effect::Reactive { .. })
+    }
+}
+
+/// Keys animated by effects: everything but the G-keys
+pub fn effect_keys() -> impl Iterator<Item = &'static LedKey> {
+    keymap::KEYS.iter().filter(|key| key.group != "gkeys")
+}
+
+/// Renders frames of an effect
+pub struct EffectRenderer {
+    effect: Effect,
+    started: Instant,
+    /// Static profile colors by address; keys not listed are off
+    base: HashMap<u8, (u8, u8, u8)>,
+    /// Time each key was last pressed, for reactive effects
+    pressed: HashMap<u8, Instant>,
+}
+
+impl EffectRenderer {
+    pub fn new(effect: Effect, base: &[(u8, (u8, u8, u8))], now: Instant) -> Self {
+        Self {
+            effect,
+            started: now,
+            base: base.iter().copied().collect(),
+            pressed: HashMap::new(),
+        }
+    }
+
+    /// Note a key press (evdev key code)
+    pub fn key_pressed(&mut self, code: u16, now: Instant) {
+        if let Some(key) = keymap::by_key_code(code) {
+            self.pressed.insert(key.address, now);
+        }
+    }
+
+    /// Colors of every effect key at `now`, as (LED address, color) pairs
+    pub fn frame(&mut self, now: Instant) -> Vec<(u8, (u8, u8, u8))> {
+        let t = now.duration_since(self.started);
+        match self.effect {
+            Effect::Breathing {
+                ref color,
+                period_ms,
+            } => {
+                let level = (1.0 - (TAU * phase(t, period_ms)).cos()) / 2.0;
+                let color = scale(color.rgb(), level);
+                effect_keys().map(|key| (key.address, color)).collect()
+            }
+            Effect::Cycle { period_ms } => {
+                let color = hue(phase(t, period_ms));
+                effect_keys().map(|key| (key.address, color)).collect()
+            }
+            Effect::Wave { period_ms } => effect_keys()
+                .map(|key| {
+                    let offset = f32::from(key.column) / f32::from(keymap::COLUMNS);
+                    (
+                        key.address,
+                        hue((phase(t, period_ms) - offset).rem_euclid(1.0)),
+                    )
+                })
+                .collect(),
+            Effect::Reactive { ref color, fade_ms } => {
+                let fade = Duration::from_millis(fade_ms);
+                self.pressed.retain(|_, at| now.duration_since(*at) < fade);
+                effect_keys()
+                    .map(|key| {
+                        let base = self.base.get(&key.address).copied().unwrap_or((0, 0, 0));
+                        let color = match self.pressed.get(&key.address) {
+                            Some(at) => {
+                                let left = 1.0
+                                    - now.duration_since(*at).as_secs_f32() / fade.as_secs_f32();
+                                blend(base, color.rgb(), left)
+                            }
+                            None => base,
+                        };
+                        (key.address, color)
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Position within a repeating period, from 0 to 1
+fn phase(t: Duration, period_ms: u64) -> f32 {
+    let period = period_ms.max(1);
+    (t.as_millis() % u128::from(period)) as f32 / period as f32
+}
+
+/// Fully saturated color at a hue from 0 to 1
+fn hue(h: f32) -> (u8, u8, u8) {
+    let sector = h * 6.0;
+    let x = 1.0 - (sector % 2.0 - 1.0).abs();
+    let (r, g, b) = match sector as u32 {
+        0 => (1.0, x, 0.0),
+        1 => (x, 1.0, 0.0),
+        2 => (0.0, 1.0, x),
+        3 => (0.0, x, 1.0),
+        4 => (x, 0.0, 1.0),
+        _ => (1.0, 0.0, x),
+    };
+    (
+        (r * 255.0).round() as u8,
+        (g * 255.0).round() as u8,
+        (b * 255.0).round() as u8,
+    )
+}
+
+/// Scale a color's brightness by `level` (0 to 1)
+fn scale((r, g, b): (u8, u8, u8), level: f32) -> (u8, u8, u8) {
+    let level = level.clamp(0.0, 1.0);
+    let channel = |c: u8| (f32::from(c) * level).round() as u8;
+    (channel(r), channel(g), channel(b))
+}
+
+/// Mix `to` into `from` by `amount` (0 to 1)
+fn blend(from: (u8, u8, u8), to: (u8, u8, u8), amount: f32) -> (u8, u8, u8) {
+    let amount = amount.clamp(0.0, 1.0);
+    let channel =
+        |a: u8, b: u8| (f32::from(a) + (f32::from(b) - f32::from(a)) * amount).round() as u8;
+    (
+        channel(from.0, to.0),
+        channel(from.1, to.1),
+        channel(from.2, to.2),
+    )
+}
+
+/// Name of a running process from `names` (compared case-insensitively)
+pub fn external_tool_running(names: &[String]) -> Option<String> {
+    if names.is_empty() {
+        return None;
+    }
+    let entries = fs::read_dir("/proc").ok()?;
+    entries
+        .flatten()
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .bytes()
+                .all(|b| b.is_ascii_digit())
+        })
+        .filter_map(|entry| fs::read_to_string(entry.path().join("comm")).ok())
+        .map(|comm| comm.trim_end().to_string())
+        .find(|comm| names.iter().any(|name| name.eq_ignore_ascii_case(comm)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn color_of(frame: &[(u8, (u8, u8, u8))], name: &str) -> (u8, u8, u8) {
+        let address = keymap::by_name(name).unwrap().address;
+        frame.iter().find(|&&(a, _)| a == address).unwrap().1
+    }
+
+    #[test]
+    fn test_effects() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let white = RgbColor {
+            r: 255,
+            g: 255,
+            b: 255,
+        };
+
+        let breathing = Effect::Breathing {
+            color: white.clone(),
+            period_ms: 1000,
+        };
+        let mut renderer = EffectRenderer::new(breathing, &[], start);
+        let frame = renderer.frame(at(0));
+        assert_eq!(frame.len(), keymap::KEYS.len() - 5);
+        assert_eq!(color_of(&frame, "esc"), (0, 0, 0));
+        assert_eq!(color_of(&renderer.frame(at(500)), "esc"), (255, 255, 255));
+
+        let mut renderer = EffectRenderer::new(Effect::Wave { period_ms: 1000 }, &[], start);
+        let frame = renderer.frame(at(0));
+        assert_eq!(color_of(&frame, "esc"), hue(1.0 - 1.0 / 22.0));
+        assert_ne!(color_of(&frame, "esc"), color_of(&frame, "kp_enter"));
+
+        let esc = keymap::by_name("esc").unwrap().address;
+        let reactive = Effect::Reactive {
+            color: white,
+            fade_ms: 1000,
+        };
+        let mut renderer = EffectRenderer::new(reactive, &[(esc, (0, 0, 100))], start);
+        renderer.key_pressed(1, at(0)); // KEY_ESC
+        assert_eq!(color_of(&renderer.frame(at(0)), "esc"), (255, 255, 255));
+        assert_eq!(color_of(&renderer.frame(at(500)), "esc"), (128, 128, 178));
+        assert_eq!(color_of(&renderer.frame(at(1000)), "esc"), (0, 0, 100));
+        assert_eq!(color_of(&renderer.frame(at(1000)), "tab"), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_hue() {
+        assert_eq!(hue(0.0), (255, 0, 0));
+        assert_eq!(hue(1.0 / 3.0), (0, 255, 0));
+        assert_eq!(hue(2.0 / 3.0), (0, 0, 255));
+    }
+
+    #[test]
+    fn test_parse_effect() {
+        let effect: Effect =
+            serde_json::from_str(r##"{ "breathing": { "color": "#ff0000" } }"##).unwrap();
+        assert_eq!(
+            effect,
+            Effect::Breathing {
+                color: RgbColor { r: 255, g: 0, b: 0 },
+                period_ms: 4000
+            }
+        );
+        let effect: Effect = serde_json::from_str(r#"{ "wave": {} }"#).unwrap();
+        assert_eq!(effect, Effect::Wave { period_ms: 3000 });
+    }
+}
@@ -1,5 +1,7 @@
 //! G-key HID event definitions and parsing
 
+use std::collections::BTreeMap;
+
 /// Events from the keyboard
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Event {
@@ -120,46 +122,6 @@ pub fn mr_led_command(on: bool) -> [u8; 20] {
     cmd
 }
 
-/// G-key LED color command
-/// Sets the RGB color for a single G-key (1-5)
-/// Command: [0x11, 0xff, 0x10, 0x6c, r, g, b, key_addr, 0xff, ...]
-/// G-key addresses: G1=0xb4, G2=0xb5, G3=0xb6, G4=0xb7, G5=0xb8
-pub fn gkey_led_command(gkey: u8, r: u8, g: u8, b: u8) -> [u8; 20] {
-    let mut cmd = [0u8; 20];
-    cmd[0] = 0x11;
-    cmd[1] = 0xff;
-    cmd[2] = 0x10;
-    cmd[3] = 0x6c;
-    cmd[4] = r;
-    cmd[5] = g;
-    cmd[6] = b;
-    // G-key address = gkey + 0xb3 (so G1=0xb4, G2=0xb5, etc.)
-    cmd[7] = gkey.saturating_add(0xb3);
-    cmd[8] = 0xff; // Terminator
-    cmd
-}
-
-/// Set all G-keys (1-5) to the same color in a single command
-/// Command: [0x11, 0xff, 0x10, 0x6c, r, g, b, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xff, ...]
-pub fn all_gkeys_led_command(r: u8, g: u8, b: u8) -> [u8; 20] {
-    let mut cmd = [0u8; 20];
-    cmd[0] = 0x11;
-    cmd[1] = 0xff;
-    cmd[2] = 0x10;
-    cmd[3] = 0x6c;
-    cmd[4] = r;
-    cmd[5] = g;
-    cmd[6] = b;
-    // All 5 G-key addresses
-    cmd[7] = 0xb4;  // G1
-    cmd[8] = 0xb5;  // G2
-    cmd[9] = 0xb6;  // G3
-    cmd[10] = 0xb7; // G4
-    cmd[11] = 0xb8; // G5
-    cmd[12] = 0xff; // Terminator
-    cmd
-}
-
 /// Commit LED changes
 /// Must be sent after setting key colors for changes to take effect
 /// Command: [0x11, 0xff, 0x10, 0x7f, ...]
@@ -205,64 +167,40 @@ pub fn direct_mode_init_commands() -> [[u8; 20]; 4] {
     [cmd1, cmd2, cmd3, cmd4]
 }
 
-/// All G815 key addresses for per-key LED control
-/// Addresses need offset transformation per g810-led:
-/// - Standard keys: HID code - 0x03
-/// - Modifiers: HID code - 0x78
-/// - G-keys: HID code + 0xB3
-/// - Logo: HID code + 0xD1
-const G815_ALL_KEYS: &[u8] = &[
-    // Letters A-Z: HID 0x04-0x1D, offset -0x03 = 0x01-0x1A
-    0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B,
-    0x0C, 0x0D, 0x0E, 0x0F, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16,
-    0x17, 0x18, 0x19, 0x1A,
-    // Numbers 1-0: HID 0x1E-0x27, offset -0x03 = 0x1B-0x24
-    0x1B, 0x1C, 0x1D, 0x1E, 0x1F, 0x20, 0x21, 0x22, 0x23, 0x24,
-    // Enter through symbols: HID 0x28-0x38, offset -0x03 = 0x25-0x35
-    0x25, 0x26, 0x27, 0x28, 0x29, 0x2A, 0x2B, 0x2C,
-    0x2D, 0x2E, 0x2F, 0x30, 0x31, 0x32, 0x33, 0x34, 0x35,
-    // Caps + F1-F12: HID 0x39-0x45, offset -0x03 = 0x36-0x42
-    0x36, 0x37, 0x38, 0x39, 0x3A, 0x3B, 0x3C, 0x3D, 0x3E, 0x3F, 0x40, 0x41, 0x42,
-    // Print/Scroll/Pause + Navigation + Arrows: HID 0x46-0x52, offset -0x03 = 0x43-0x4F
-    0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4A, 0x4B, 0x4C, 0x4D, 0x4E, 0x4F,
-    // Numpad: HID 0x53-0x63, offset -0x03 = 0x50-0x60
-    0x50, 0x51, 0x52, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x5B, 0x5C,
-    0x5D, 0x5E, 0x5F, 0x60,
-    // ISO + Menu: HID 0x64-0x65, offset -0x03 = 0x61-0x62
-    0x61, 0x62,
-    // Modifiers left + right: HID 0xE0-0xE7, offset -0x78 = 0x68-0x6F
-    0x68, 0x69, 0x6A, 0x6B, 0x6C, 0x6D, 0x6E, 0x6F,
-    // Media keys: raw HID addresses (no offset, like G-keys)
-    0x9B, 0x9C, 0x9D, 0x9E,
-    // Lighting indicator: raw HID address
-    0x99,
-    // Logo: HID 0x01 + 0xD1 = 0xD2
-    0xD2,
-    // G-keys: raw HID addresses 0xB4-0xB8
-    0xB4, 0xB5, 0xB6, 0xB7, 0xB8,
-];
-
-/// Generate HID commands to set the entire keyboard to one color
-/// Uses per-key format (0x1F frame type) for maximum compatibility
-/// Returns a Vec of 20-byte commands that should be sent followed by led_commit_command()
-pub fn full_keyboard_color_commands(r: u8, g: u8, b: u8) -> Vec<[u8; 20]> {
-    G815_ALL_KEYS
-        .iter()
-        .map(|&key| {
+/// Multi-key LED color commands (0x6c frame type)
+/// Command: [0x11, 0xff, 0x10, 0x6c, r, g, b, key_addr..., 0xff]
+/// Keys with the same color share a report, up to 13 per report.
+/// G-keys (G1=0xb4 ... G5=0xb8) work without direct mode; other keys need
+/// direct_mode_init_commands() first. Send led_commit_command() after.
+pub fn multi_key_commands(colors: &[(u8, (u8, u8, u8))]) -> Vec<[u8; 20]> {
+    let mut by_color: BTreeMap<(u8, u8, u8), Vec<u8>> = BTreeMap::new();
+    for &(key, color) in colors {
+        by_color.entry(color).or_default().push(key);
+    }
+    let mut commands = Vec::new();
+    for ((r, g, b), keys) in by_color {
+        for chunk in keys.chunks(MULTI_KEY_MAX) {
             let mut cmd = [0u8; 20];
             cmd[0] = 0x11;
             cmd[1] = 0xff;
             cmd[2] = 0x10;
-            cmd[3] = 0x1f; // Single-key frame type (LOGITECH_G815_ZONE_FRAME_TYPE_LITTLE)
-            cmd[4] = key;
-            cmd[5] = r;
-            cmd[6] = g;
-            cmd[7] = b;
-            cmd
-        })
-        .collect()
+            cmd[3] = 0x6c;
+            cmd[4] = r;
+            cmd[5] = g;
+            cmd[6] = b;
+            cmd[7..7 + chunk.len()].copy_from_slice(chunk);
+            if chunk.len() < MULTI_KEY_MAX {
+                cmd[7 + chunk.len()] = 0xff; // Terminator
+            }
+            commands.push(cmd);
+        }
+    }
+    commands
 }
 
+/// Most keys in one multi-key report (bytes 7-19)
+const MULTI_KEY_MAX: usize = 13;
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -293,4 +231,22 @@ mod tests {
         let mr = [0x11, 0xff, 0x0c, 0x00, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
         assert_eq!(parse_report(&mr), Some(Event::MRKey));
     }
+
+    #[test]
+    fn test_multi_key_commands() {
+        let red = (255, 0, 0);
+        let mut colors: Vec<_> = (1..=14u8).map(|key| (key, red)).collect();
+        colors.push((0xb4, (0, 0, 255)));
+        let cmds = multi_key_commands(&colors);
+        assert_eq!(cmds.len(), 3);
+        // Grouped by color: one blue key, then 14 red keys over two reports
+        assert_eq!(
+            cmds[0][..9],
+            [0x11, 0xff, 0x10, 0x6c, 0, 0, 255, 0xb4, 0xff]
+        );
+        assert_eq!(cmds[1][4..7], [255, 0, 0]);
+        // A full report has 13 keys and no terminator
+        assert_eq!(cmds[1][7..], (1..=13).collect::<Vec<u8>>()[..]);
+        assert_eq!(cmds[2][7..9], [14, 0xff]);
+    }
 }
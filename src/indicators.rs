@@ -0,0 +1,271 @@
+//! Status indicators on single key LEDs
+//!
+//! Each indicator watches its source on its own thread and passes the color
+//! for the source's current value to the LED thread whenever it changes.
+
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use evdev::{Device as EvdevDevice, LedType};
+
+use crate::command;
+use crate::config::{Indicator, IndicatorSource, RunOptions};
+use crate::recording;
+
+/// Values pushed over the control socket for `ipc` indicators, by name
+pub type PushedValues = Arc<Mutex<HashMap<String, String>>>;
+
+/// How often lock key states and pushed values are checked
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How often to look for the keyboard while it is missing
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Exits 0 while the default microphone is muted
+const MIC_MUTE_COMMAND: &str = "pactl get-source-mute @DEFAULT_SOURCE@ | grep -q yes";
+
+/// Threads watching the configured indicators, stopped when dropped
+pub struct IndicatorWatcher {
+    indicators: Vec<Indicator>,
+    stop_flag: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl IndicatorWatcher {
+    /// Start a thread per indicator, calling `on_change` with the key's LED
+    /// address and its color (None for the key's usual color)
+    pub fn start(
+        indicators: Vec<Indicator>,
+        pushed: PushedValues,
+        on_change: impl Fn(u8, Option<(u8, u8, u8)>) + Clone + Send + 'static,
+    ) -> Result<Self> {
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let mut watcher = Self {
+            indicators: indicators.clone(),
+            stop_flag: stop_flag.clone(),
+            threads: Vec::new(),
+        };
+        for indicator in indicators {
+            let source = Source::new(&indicator, pushed.clone());
+            let on_change = on_change.clone();
+            let stop_flag = stop_flag.clone();
+            let thread = thread::Builder::new()
+                .name(format!("indicator-{}", indicator.key))
+                .spawn(move || watch(indicator, source, on_change, stop_flag))
+                .context("Failed to spawn indicator thread")?;
+            watcher.threads.push(thread);
+        }
+        log::debug!("Watching {} indicators", watcher.indicators.len());
+        Ok(watcher)
+    }
+
+    /// The indicators being watched
+    pub fn indicators(&self) -> &[Indicator] {
+        &self.indicators
+    }
+}
+
+impl Drop for IndicatorWatcher {
+    fn drop(&mut self) {
+        // Wait so a stopping thread can't change a key after its replacement
+        self.stop_flag.store(true, Ordering::SeqCst);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Source of an indicator's value
+enum Source {
+    Lock {
+        led: LedType,
+        device: Option<Box<EvdevDevice>>,
+        last_attempt: Option<Instant>,
+    },
+    Command {
+        command: String,
+        interval: Duration,
+    },
+    Pushed {
+        name: String,
+        values: PushedValues,
+    },
+}
+
+impl Source {
+    fn new(indicator: &Indicator, pushed: PushedValues) -> Self {
+        let lock = |led| Source::Lock {
+            led,
+            device: None,
+            last_attempt: None,
+        };
+        let command = |command: &str| Source::Command {
+            command: command.to_string(),
+            interval: Duration::from_secs(indicator.interval_secs),
+        };
+        match indicator.source {
+            IndicatorSource::CapsLock => lock(LedType::LED_CAPSL),
+            IndicatorSource::NumLock => lock(LedType::LED_NUML),
+            IndicatorSource::ScrollLock => lock(LedType::LED_SCROLLL),
+            IndicatorSource::MicMute => command(MIC_MUTE_COMMAND),
+            IndicatorSource::Command(ref shell_command) => command(shell_command),
+            IndicatorSource::Ipc(ref name) => Source::Pushed {
+                name: name.clone(),
+                values: pushed,
+            },
+        }
+    }
+
+    /// Time between reads
+    fn interval(&self) -> Duration {
+        match self {
+            Source::Command { interval, .. } => *interval,
+            _ => POLL_INTERVAL,
+        }
+    }
+
+    /// The current value, or None if it can't be read
+    fn read(&mut self, stop_flag: &AtomicBool) -> Option<String> {
+        match self {
+            Source::Lock {
+                led,
+                device,
+                last_attempt,
+            } => {
+                if device.is_none() && last_attempt.is_none_or(|at| at.elapsed() >= RETRY_INTERVAL)
+                {
+                    *last_attempt = Some(Instant::now());
+                    *device = recording::find_keyboard_evdev()
+                        .and_then(|path| EvdevDevice::open(path).ok())
+                        .map(Box::new);
+                }
+                match device.as_ref()?.get_led_state() {
+                    Ok(state) => Some(on_off(state.contains(*led))),
+                    Err(e) => {
+                        log::debug!("Lost keyboard lock key state: {}", e);
+                        *device = None;
+                        None
+                    }
+                }
+            }
+            Source::Command { command, interval } => {
+                let mut cmd = command::build(command, &RunOptions::default()).ok()?;
+                cmd.stderr(Stdio::null());
+                let result = command::run_captured(cmd, Some(*interval), || {
+                    stop_flag.load(Ordering::SeqCst)
+                });
+                match result {
+                    Ok((status, _)) => Some(on_off(status.success())),
+                    Err(e) => {
+                        log::debug!("Indicator command failed: {:#}", e);
+                        None
+                    }
+                }
+            }
+            Source::Pushed { name, values } => {
+                let values = values.lock().unwrap_or_else(|e| e.into_inner());
+                values.get(name.as_str()).cloned()
+            }
+        }
+    }
+}
+
+fn on_off(on: bool) -> String {
+    if on { "on" } else { "off" }.to_string()
+}
+
+/// Read `source` until stopped, passing on color changes
+fn watch(
+    indicator: Indicator,
+    mut source: Source,
+    on_change: impl Fn(u8, Option<(u8, u8, u8)>),
+    stop_flag: Arc<AtomicBool>,
+) {
+    let mut shown = None;
+    while !stop_flag.load(Ordering::SeqCst) {
+        let value = source.read(&stop_flag);
+        let color = value
+            .and_then(|value| indicator.colors.get(&value))
+            .map(|color| color.rgb());
+        if color != shown && !stop_flag.load(Ordering::SeqCst) {
+            on_change(indicator.address, color);
+            shown = color;
+        }
+
+        let next = Instant::now() + source.interval();
+        while !stop_flag.load(Ordering::SeqCst) && Instant::now() < next {
+            thread::sleep(POLL_INTERVAL.min(next.saturating_duration_since(Instant::now())));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::mpsc;
+
+    use crate::config::RgbColor;
+
+    fn indicator(key: &str, source: IndicatorSource, colors: &[(&str, RgbColor)]) -> Indicator {
+        Indicator {
+            key: key.to_string(),
+            source,
+            colors: colors
+                .iter()
+                .map(|(value, color)| (value.to_string(), color.clone()))
+                .collect::<BTreeMap<_, _>>(),
+            interval_secs: 1,
+            address: crate::keymap::by_name(key).unwrap().address,
+        }
+    }
+
+    #[test]
+    fn test_watcher() {
+        let red = RgbColor { r: 255, g: 0, b: 0 };
+        let green = RgbColor { r: 0, g: 255, b: 0 };
+        let pushed = PushedValues::default();
+        let (tx, rx) = mpsc::channel();
+        let watcher = IndicatorWatcher::start(
+            vec![
+                indicator(
+                    "logo",
+                    IndicatorSource::Command("exit 3".to_string()),
+                    &[("off", red.clone())],
+                ),
+                indicator(
+                    "g5",
+                    IndicatorSource::Ipc("ci".to_string()),
+                    &[("passed", green)],
+                ),
+            ],
+            pushed.clone(),
+            move |address, color| {
+                let _ = tx.send((address, color));
+            },
+        )
+        .unwrap();
+        let next = || rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert_eq!(next(), (0xd2, Some((255, 0, 0))));
+        pushed
+            .lock()
+            .unwrap()
+            .insert("ci".to_string(), "passed".to_string());
+        assert_eq!(next(), (0xb8, Some((0, 255, 0))));
+        // Values without a color give the key back
+        pushed
+            .lock()
+            .unwrap()
+            .insert("ci".to_string(), "running".to_string());
+        assert_eq!(next(), (0xb8, None));
+
+        drop(watcher);
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,164 @@
+//! Control socket for commands from other processes
+//!
+//! A Unix stream socket in `$XDG_RUNTIME_DIR` accepting one text command per
+//! connection (e.g. `cancel`) and replying with a single line. The socket is
+//! only created in a directory no other user can enter, so there is no window
+//! in which someone else could connect.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+/// Timeout for a client waiting on the daemon's reply
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default control socket path, in the user's private runtime directory
+pub fn socket_path() -> Result<PathBuf> {
+    let dir = dirs::runtime_dir().context("XDG_RUNTIME_DIR is not set")?;
+    Ok(dir.join("gkeys-rs.sock"))
+}
+
+/// Check that only the current user can reach sockets in `dir`
+fn check_private_dir(dir: &Path) -> Result<()> {
+    let metadata =
+        fs::metadata(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+    // SAFETY: getuid has no preconditions and cannot fail
+    let uid = unsafe { libc::getuid() };
+    if metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        bail!(
+            "{} must be owned by the current user and closed to others (mode 0700)",
+            dir.display()
+        );
+    }
+    Ok(())
+}
+
+/// Command handler: takes the command line, returns the reply text
+pub type Handler = Box<dyn Fn(&str) -> Result<String> + Send>;
+
+/// Listening control socket, removed when dropped
+pub struct IpcServer {
+    path: PathBuf,
+}
+
+impl IpcServer {
+    /// Bind the socket and handle connections on a background thread
+    pub fn start(path: &Path, handler: Handler) -> Result<Self> {
+        check_private_dir(path.parent().unwrap_or(Path::new(".")))?;
+        if UnixStream::connect(path).is_ok() {
+            bail!(
+                "Another gkeys-rs instance is listening on {}",
+                path.display()
+            );
+        }
+        // Remove a stale socket left by a crashed instance
+        let _ = fs::remove_file(path);
+
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Failed to bind control socket {}", path.display()))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Failed to set permissions on {}", path.display()))?;
+
+        thread::Builder::new()
+            .name("ipc".into())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            if let Err(e) = handle_client(stream, &handler) {
+                                log::warn!("Control socket client error: {:#}", e);
+                            }
+                        }
+                        Err(e) => log::warn!("Control socket accept failed: {}", e),
+                    }
+                }
+            })
+            .context("Failed to spawn control socket thread")?;
+
+        log::info!("Listening for commands on {}", path.display());
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for IpcServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Read one command line and write the handler's reply
+fn handle_client(stream: UnixStream, handler: &Handler) -> Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let command = line.trim();
+    log::debug!("Control command: {}", command);
+
+    let reply = match handler(command) {
+        Ok(reply) => format!("ok {}\n", reply),
+        Err(e) => format!("error {:#}\n", e),
+    };
+    (&stream).write_all(reply.trim_end_matches(' ').as_bytes())?;
+    Ok(())
+}
+
+/// Send a command to a running daemon and return its reply
+pub fn send_command(path: &Path, command: &str) -> Result<String> {
+    let mut stream = UnixStream::connect(path).with_context(|| {
+        format!(
+            "Could not connect to {} - is gkeys-rs running?",
+            path.display()
+        )
+    })?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    let reply = reply.trim();
+    match reply.split_once(' ').unwrap_or((reply, "")) {
+        ("ok", message) => Ok(message.to_string()),
+        ("error", message) => bail!("{}", message),
+        _ => bail!("Unexpected reply from daemon: {:?}", reply),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = std::env::temp_dir().join(format!("gkeys-rs-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        let path = dir.join("gkeys-rs.sock");
+        // Other users could reach the socket
+        assert!(IpcServer::start(&path, Box::new(|_| Ok(String::new()))).is_err());
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap();
+        let server = IpcServer::start(
+            &path,
+            Box::new(|command| match command {
+                "ping" => Ok("pong".to_string()),
+                _ => bail!("Unknown command '{}'", command),
+            }),
+        )
+        .unwrap();
+
+        assert_eq!(send_command(&path, "ping").unwrap(), "pong");
+        let err = send_command(&path, "bogus").unwrap_err();
+        assert!(err.to_string().contains("Unknown command 'bogus'"));
+        drop(server);
+        let _ = fs::remove_dir(&dir);
+    }
+}
@@ -0,0 +1,218 @@
+//! Named G815 per-key LED addresses
+//!
+//! Addresses follow g810-led's offsets from the HID usage codes:
+//! - Standard keys: HID code - 0x03
+//! - Modifiers: HID code - 0x78
+//! - G-keys: HID code + 0xB3
+//! - Logo: HID code + 0xD1
+//!
+//! Media keys and the lighting button use raw addresses.
+
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use crate::uinput::VirtualKeyboard;
+
+/// A key with its own LED
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LedKey {
+    /// Name used in the `lighting` config
+    pub name: &'static str,
+    /// Address used in LED reports
+    pub address: u8,
+    /// Group the key belongs to, also usable as a name in `lighting`
+    pub group: &'static str,
+    /// Approximate column from the left edge (G-keys are 0), for effects
+    pub column: u8,
+}
+
+const fn key(name: &'static str, address: u8, group: &'static str, column: u8) -> LedKey {
+    LedKey {
+        name,
+        address,
+        group,
+        column,
+    }
+}
+
+/// Every G815 key with an LED
+#[rustfmt::skip]
+pub const KEYS: &[LedKey] = &[
+    // Letters A-Z: HID 0x04-0x1D
+    key("a", 0x01, "letters", 2), key("b", 0x02, "letters", 7), key("c", 0x03, "letters", 5),
+    key("d", 0x04, "letters", 4), key("e", 0x05, "letters", 4), key("f", 0x06, "letters", 5),
+    key("g", 0x07, "letters", 6), key("h", 0x08, "letters", 7), key("i", 0x09, "letters", 9),
+    key("j", 0x0A, "letters", 8), key("k", 0x0B, "letters", 9), key("l", 0x0C, "letters", 10),
+    key("m", 0x0D, "letters", 9), key("n", 0x0E, "letters", 8), key("o", 0x0F, "letters", 10),
+    key("p", 0x10, "letters", 11), key("q", 0x11, "letters", 2), key("r", 0x12, "letters", 5),
+    key("s", 0x13, "letters", 3), key("t", 0x14, "letters", 6), key("u", 0x15, "letters", 8),
+    key("v", 0x16, "letters", 6), key("w", 0x17, "letters", 3), key("x", 0x18, "letters", 4),
+    key("y", 0x19, "letters", 7), key("z", 0x1A, "letters", 3),
+    // Numbers 1-0: HID 0x1E-0x27
+    key("1", 0x1B, "numbers", 2), key("2", 0x1C, "numbers", 3), key("3", 0x1D, "numbers", 4),
+    key("4", 0x1E, "numbers", 5), key("5", 0x1F, "numbers", 6), key("6", 0x20, "numbers", 7),
+    key("7", 0x21, "numbers", 8), key("8", 0x22, "numbers", 9), key("9", 0x23, "numbers", 10),
+    key("0", 0x24, "numbers", 11),
+    // Enter through symbols: HID 0x28-0x38
+    key("enter", 0x25, "main", 14), key("esc", 0x26, "main", 1), key("backspace", 0x27, "main", 14),
+    key("tab", 0x28, "main", 1), key("space", 0x29, "main", 7), key("minus", 0x2A, "main", 12),
+    key("equal", 0x2B, "main", 13), key("left_brace", 0x2C, "main", 12),
+    key("right_brace", 0x2D, "main", 13), key("backslash", 0x2E, "main", 14),
+    key("hash", 0x2F, "main", 13), key("semicolon", 0x30, "main", 11),
+    key("apostrophe", 0x31, "main", 12), key("grave", 0x32, "main", 1),
+    key("comma", 0x33, "main", 10), key("dot", 0x34, "main", 11), key("slash", 0x35, "main", 12),
+    // Caps Lock: HID 0x39
+    key("caps_lock", 0x36, "main", 1),
+    // F1-F12: HID 0x3A-0x45
+    key("f1", 0x37, "fkeys", 3), key("f2", 0x38, "fkeys", 4), key("f3", 0x39, "fkeys", 5),
+    key("f4", 0x3A, "fkeys", 6), key("f5", 0x3B, "fkeys", 7), key("f6", 0x3C, "fkeys", 8),
+    key("f7", 0x3D, "fkeys", 9), key("f8", 0x3E, "fkeys", 10), key("f9", 0x3F, "fkeys", 11),
+    key("f10", 0x40, "fkeys", 12), key("f11", 0x41, "fkeys", 13), key("f12", 0x42, "fkeys", 14),
+    // Print/Scroll/Pause + navigation: HID 0x46-0x4E
+    key("print_screen", 0x43, "navigation", 15), key("scroll_lock", 0x44, "navigation", 16),
+    key("pause", 0x45, "navigation", 17), key("insert", 0x46, "navigation", 15),
+    key("home", 0x47, "navigation", 16), key("page_up", 0x48, "navigation", 17),
+    key("delete", 0x49, "navigation", 15), key("end", 0x4A, "navigation", 16),
+    key("page_down", 0x4B, "navigation", 17),
+    // Arrows: HID 0x4F-0x52
+    key("right", 0x4C, "arrows", 17), key("left", 0x4D, "arrows", 15),
+    key("down", 0x4E, "arrows", 16), key("up", 0x4F, "arrows", 16),
+    // Numpad: HID 0x53-0x63
+    key("num_lock", 0x50, "numpad", 18), key("kp_slash", 0x51, "numpad", 19),
+    key("kp_asterisk", 0x52, "numpad", 20), key("kp_minus", 0x53, "numpad", 21),
+    key("kp_plus", 0x54, "numpad", 21), key("kp_enter", 0x55, "numpad", 21),
+    key("kp_1", 0x56, "numpad", 18), key("kp_2", 0x57, "numpad", 19),
+    key("kp_3", 0x58, "numpad", 20), key("kp_4", 0x59, "numpad", 18),
+    key("kp_5", 0x5A, "numpad", 19), key("kp_6", 0x5B, "numpad", 20),
+    key("kp_7", 0x5C, "numpad", 18), key("kp_8", 0x5D, "numpad", 19),
+    key("kp_9", 0x5E, "numpad", 20),
+    key("kp_0", 0x5F, "numpad", 18), key("kp_dot", 0x60, "numpad", 20),
+    // ISO key next to left shift + Menu: HID 0x64-0x65
+    key("iso_backslash", 0x61, "main", 2), key("menu", 0x62, "modifiers", 13),
+    // Modifiers left + right: HID 0xE0-0xE7
+    key("left_ctrl", 0x68, "modifiers", 1), key("left_shift", 0x69, "modifiers", 1),
+    key("left_alt", 0x6A, "modifiers", 3), key("left_meta", 0x6B, "modifiers", 2),
+    key("right_ctrl", 0x6C, "modifiers", 14), key("right_shift", 0x6D, "modifiers", 14),
+    key("right_alt", 0x6E, "modifiers", 11), key("right_meta", 0x6F, "modifiers", 12),
+    // Media keys
+    key("media_next", 0x9B, "media", 20), key("media_prev", 0x9C, "media", 18),
+    key("media_play", 0x9D, "media", 19), key("media_mute", 0x9E, "media", 21),
+    // Lighting button
+    key("backlight", 0x99, "indicators", 16),
+    // Logo: HID 0x01
+    key("logo", 0xD2, "logo", 9),
+    // G-keys: HID 0x01-0x05
+    key("g1", 0xB4, "gkeys", 0), key("g2", 0xB5, "gkeys", 0), key("g3", 0xB6, "gkeys", 0),
+    key("g4", 0xB7, "gkeys", 0), key("g5", 0xB8, "gkeys", 0),
+];
+
+/// Name that selects every key
+pub const ALL: &str = "all";
+
+/// Compare names ignoring case and underscores, so "kp_enter", "kpenter"
+/// and "KP_ENTER" are the same key
+fn same_name(a: &str, b: &str) -> bool {
+    let strip = |s: &str| {
+        s.chars()
+            .filter(|&c| c != '_')
+            .map(|c| c.to_ascii_lowercase())
+            .collect::<String>()
+    };
+    strip(a) == strip(b)
+}
+
+/// Look up a single key by name
+pub fn by_name(name: &str) -> Option<&'static LedKey> {
+    KEYS.iter().find(|key| same_name(key.name, name))
+}
+
+/// Resolve a key or group name ("esc", "gkeys", "all") to LED addresses
+pub fn addresses(name: &str) -> Option<Vec<u8>> {
+    if let Some(key) = by_name(name) {
+        return Some(vec![key.address]);
+    }
+    let addresses: Vec<u8> = KEYS
+        .iter()
+        .filter(|key| same_name(ALL, name) || same_name(key.group, name))
+        .map(|key| key.address)
+        .collect();
+    (!addresses.is_empty()).then_some(addresses)
+}
+
+/// Number of columns, for effects that move across the keyboard
+pub const COLUMNS: u8 = 22;
+
+/// Look up the key with an evdev key code, to light keys as they are pressed
+pub fn by_key_code(code: u16) -> Option<&'static LedKey> {
+    static BY_CODE: LazyLock<HashMap<u16, &'static LedKey>> = LazyLock::new(|| {
+        KEYS.iter()
+            .filter_map(|key| Some((key_code(key.name)?, key)))
+            .collect()
+    });
+    BY_CODE.get(&code).copied()
+}
+
+/// evdev key code of a key, where it differs from the key's name
+fn key_code(name: &str) -> Option<u16> {
+    let name = match name {
+        // Shares KEY_BACKSLASH with the ANSI backslash key
+        "hash" => return None,
+        "iso_backslash" => "102nd",
+        "menu" => "compose",
+        "media_next" => "nextsong",
+        "media_prev" => "previoussong",
+        "media_play" => "playpause",
+        "media_mute" => "mute",
+        // Handled by the keyboard, not reported as keys
+        "backlight" | "logo" | "g1" | "g2" | "g3" | "g4" | "g5" => return None,
+        name => name,
+    };
+    VirtualKeyboard::parse_key(&name.replace('_', ""))
+}
+
+/// LED address of a G-key (1-5)
+pub fn gkey_address(gkey: u8) -> u8 {
+    gkey.saturating_add(0xb3)
+}
+
+/// G-key number (1-5) for an LED address, if it is a G-key
+pub fn gkey_at(address: u8) -> Option<u8> {
+    (gkey_address(1)..=gkey_address(5))
+        .contains(&address)
+        .then(|| address - 0xb3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names() {
+        assert_eq!(KEYS.len(), 117);
+        assert_eq!(addresses("esc"), Some(vec![0x26]));
+        assert_eq!(addresses("F1"), Some(vec![0x37]));
+        assert_eq!(addresses("kpenter"), Some(vec![0x55]));
+        assert_eq!(addresses("logo"), Some(vec![0xD2]));
+        assert_eq!(addresses("g1"), Some(vec![gkey_address(1)]));
+        assert_eq!(gkey_at(gkey_address(5)), Some(5));
+        assert_eq!(gkey_at(0xD2), None);
+        assert_eq!(addresses("gkeys").unwrap().len(), 5);
+        assert_eq!(addresses("all").unwrap().len(), KEYS.len());
+        assert_eq!(addresses("nope"), None);
+
+        assert_eq!(by_key_code(30).unwrap().name, "a"); // KEY_A
+        assert_eq!(by_key_code(96).unwrap().name, "kp_enter"); // KEY_KPENTER
+        assert_eq!(by_key_code(29).unwrap().name, "left_ctrl"); // KEY_LEFTCTRL
+        assert_eq!(by_key_code(86).unwrap().name, "iso_backslash"); // KEY_102ND
+        let mapped = KEYS.iter().filter(|key| key_code(key.name).is_some());
+        assert_eq!(mapped.count(), KEYS.len() - 8);
+
+        // Names and addresses are unique
+        for (i, a) in KEYS.iter().enumerate() {
+            for b in &KEYS[i + 1..] {
+                assert!(!same_name(a.name, b.name), "{}", a.name);
+                assert_ne!(a.address, b.address, "{}", a.name);
+            }
+        }
+    }
+}
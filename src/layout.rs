@@ -0,0 +1,692 @@
+//! Keyboard layouts for typeout
+//!
+//! Maps characters to key codes plus the modifiers (Shift, AltGr) needed to
+//! produce them. Layouts are written in XKB symbols syntax
+//! (`key <AC01> { [ a, A, ae, AE ] };`), so built-in tables and files dumped
+//! with `xkbcomp $DISPLAY keymap.xkb` share the same parser.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::uinput::keys;
+
+/// Key press needed to produce a character
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyStroke {
+    pub key: u16,
+    pub shift: bool,
+    pub altgr: bool,
+}
+
+impl KeyStroke {
+    /// Number of modifiers needed, used to prefer the simplest stroke
+    fn modifier_count(&self) -> u8 {
+        self.shift as u8 + self.altgr as u8
+    }
+}
+
+/// Character to key stroke table for one keyboard layout
+#[derive(Debug, Clone)]
+pub struct Layout {
+    name: String,
+    chars: HashMap<char, KeyStroke>,
+}
+
+impl Layout {
+    /// Resolve a `keyboard_mapping` value: a built-in layout name or a path to
+    /// an XKB symbols/keymap file. Empty selects US QWERTY.
+    pub fn from_name(mapping: &str) -> Result<Self> {
+        let mapping = mapping.trim();
+        if let Some(layout) = Self::builtin(mapping) {
+            return Ok(layout);
+        }
+        if mapping.contains('/') || mapping.ends_with(".xkb") {
+            return Self::from_xkb_file(Path::new(mapping));
+        }
+        bail!(
+            "Unknown keyboard_mapping '{}' (expected us, gb, de, fr or a path to an XKB file)",
+            mapping
+        )
+    }
+
+    /// Get a built-in layout by name
+    pub fn builtin(name: &str) -> Option<Self> {
+        let (name, symbols) = match name.to_lowercase().as_str() {
+            "" | "us" | "en" => ("us", US_SYMBOLS),
+            "gb" | "uk" => ("gb", GB_SYMBOLS),
+            "de" => ("de", DE_SYMBOLS),
+            "fr" => ("fr", FR_SYMBOLS),
+            _ => return None,
+        };
+        Some(Self::parse_xkb(name, symbols).expect("built-in layout must parse"))
+    }
+
+    /// US QWERTY layout
+    pub fn us() -> Self {
+        Self::parse_xkb("us", US_SYMBOLS).expect("built-in layout must parse")
+    }
+
+    /// Load a layout from an XKB symbols or full keymap file
+    pub fn from_xkb_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read keyboard layout {}", path.display()))?;
+        let layout = Self::parse_xkb(&path.display().to_string(), &content)
+            .with_context(|| format!("Failed to parse keyboard layout {}", path.display()))?;
+        log::info!(
+            "Loaded keyboard layout from {} ({} characters)",
+            path.display(),
+            layout.chars.len()
+        );
+        Ok(layout)
+    }
+
+    /// Parse `key <NAME> { [ level1, level2, level3, level4 ] };` entries.
+    /// Only the first group is used; keys outside the main typing block and
+    /// dead keys are ignored.
+    pub fn parse_xkb(name: &str, content: &str) -> Result<Self> {
+        let mut layout = Self {
+            name: name.to_string(),
+            chars: HashMap::new(),
+        };
+        // Whitespace keys are the same on every layout
+        layout.insert(' ', keys::KEY_SPACE, 0);
+        layout.insert('\n', keys::KEY_ENTER, 0);
+        layout.insert('\t', keys::KEY_TAB, 0);
+
+        let mut rest = content;
+        while let Some(start) = rest.find("key <") {
+            rest = &rest[start + 5..];
+            let end = rest.find('>').context("Unterminated key name")?;
+            let key_name = &rest[..end];
+            let body_end = rest.find("};").context("Unterminated key definition")?;
+            let body = &rest[end + 1..body_end];
+            rest = &rest[body_end + 2..];
+
+            let Some(code) = xkb_key_code(key_name) else {
+                continue;
+            };
+            // Symbol list is the first '[' after '{' or '=' (skips "symbols[Group1]")
+            let Some(open) = body
+                .match_indices('[')
+                .map(|(i, _)| i)
+                .find(|&i| matches!(body[..i].trim_end().chars().last(), Some('{' | '=')))
+            else {
+                continue;
+            };
+            let close = body[open..]
+                .find(']')
+                .with_context(|| format!("Unterminated symbols for <{}>", key_name))?;
+            let symbols = &body[open + 1..open + close];
+
+            for (level, sym) in symbols.split(',').map(str::trim).enumerate().take(4) {
+                if let Some(c) = keysym_to_char(sym) {
+                    layout.insert(c, code, level);
+                }
+            }
+        }
+
+        if layout.chars.len() <= 3 {
+            bail!("No key definitions found");
+        }
+        Ok(layout)
+    }
+
+    /// Add a character at the given shift level (0-3), keeping the stroke
+    /// with fewest modifiers if the character is already mapped
+    fn insert(&mut self, c: char, key: u16, level: usize) {
+        let stroke = KeyStroke {
+            key,
+            shift: level % 2 == 1,
+            altgr: level >= 2,
+        };
+        match self.chars.get(&c) {
+            Some(existing) if existing.modifier_count() <= stroke.modifier_count() => {}
+            _ => {
+                self.chars.insert(c, stroke);
+            }
+        }
+    }
+
+    /// Look up the key stroke for a character
+    pub fn lookup(&self, c: char) -> Option<KeyStroke> {
+        self.chars.get(&c).copied()
+    }
+
+    /// Layout name (built-in name or file path)
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self::us()
+    }
+}
+
+/// Map an XKB key name to a linux key code (main typing block only)
+fn xkb_key_code(name: &str) -> Option<u16> {
+    // Row offsets: xkb <AE01> is KEY_1, <AD01> KEY_Q, <AC01> KEY_A, <AB01> KEY_Z
+    let row_base = |row: &str| match row {
+        "AE" => Some(keys::KEY_1),
+        "AD" => Some(keys::KEY_Q),
+        "AC" => Some(keys::KEY_A),
+        "AB" => Some(keys::KEY_Z),
+        _ => None,
+    };
+    match name {
+        "TLDE" => Some(keys::KEY_GRAVE),
+        "BKSL" | "AC12" => Some(keys::KEY_BACKSLASH),
+        "LSGT" => Some(keys::KEY_102ND),
+        "SPCE" => Some(keys::KEY_SPACE),
+        _ if name.len() == 4 => {
+            let base = row_base(&name[..2])?;
+            let index: u16 = name[2..].parse().ok()?;
+            let max = match &name[..2] {
+                "AE" | "AD" => 12,
+                "AC" => 11,
+                _ => 10,
+            };
+            (1..=max).contains(&index).then(|| base + index - 1)
+        }
+        _ => None,
+    }
+}
+
+/// Convert an XKB keysym name to the character it produces.
+/// Accepts X11 keysym names, `U20AC` Unicode names and `0x...` keysym codes.
+/// Returns None for dead keys, NoSymbol and non-printing keysyms.
+pub fn keysym_to_char(sym: &str) -> Option<char> {
+    let mut chars = sym.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(c);
+    }
+    if let Some(hex) = sym.strip_prefix('U') {
+        if (4..=6).contains(&hex.len()) {
+            if let Ok(cp) = u32::from_str_radix(hex, 16) {
+                return char::from_u32(cp);
+            }
+        }
+    }
+    if let Some(hex) = sym.strip_prefix("0x") {
+        let code = u32::from_str_radix(hex, 16).ok()?;
+        return match code {
+            0x20..=0x7e | 0xa0..=0xff => char::from_u32(code),
+            0x0100_0000.. => char::from_u32(code - 0x0100_0000),
+            _ => None,
+        };
+    }
+    KEYSYM_NAMES
+        .iter()
+        .find(|(name, _)| *name == sym)
+        .map(|&(_, c)| c)
+}
+
+/// X11 keysym names for Latin-1 and common typographic characters
+const KEYSYM_NAMES: &[(&str, char)] = &[
+    ("space", ' '),
+    ("exclam", '!'),
+    ("quotedbl", '"'),
+    ("numbersign", '#'),
+    ("dollar", '$'),
+    ("percent", '%'),
+    ("ampersand", '&'),
+    ("apostrophe", '\''),
+    ("parenleft", '('),
+    ("parenright", ')'),
+    ("asterisk", '*'),
+    ("plus", '+'),
+    ("comma", ','),
+    ("minus", '-'),
+    ("period", '.'),
+    ("slash", '/'),
+    ("colon", ':'),
+    ("semicolon", ';'),
+    ("less", '<'),
+    ("equal", '='),
+    ("greater", '>'),
+    ("question", '?'),
+    ("at", '@'),
+    ("bracketleft", '['),
+    ("backslash", '\\'),
+    ("bracketright", ']'),
+    ("asciicircum", '^'),
+    ("underscore", '_'),
+    ("grave", '`'),
+    ("braceleft", '{'),
+    ("bar", '|'),
+    ("braceright", '}'),
+    ("asciitilde", '~'),
+    ("nobreakspace", '\u{a0}'),
+    ("exclamdown", '¡'),
+    ("cent", '¢'),
+    ("sterling", '£'),
+    ("currency", '¤'),
+    ("yen", '¥'),
+    ("brokenbar", '¦'),
+    ("section", '§'),
+    ("diaeresis", '¨'),
+    ("copyright", '©'),
+    ("ordfeminine", 'ª'),
+    ("guillemotleft", '«'),
+    ("guillemetleft", '«'),
+    ("notsign", '¬'),
+    ("hyphen", '\u{ad}'),
+    ("registered", '®'),
+    ("macron", '¯'),
+    ("degree", '°'),
+    ("plusminus", '±'),
+    ("twosuperior", '²'),
+    ("threesuperior", '³'),
+    ("acute", '´'),
+    ("mu", 'µ'),
+    ("paragraph", '¶'),
+    ("periodcentered", '·'),
+    ("cedilla", '¸'),
+    ("onesuperior", '¹'),
+    ("masculine", 'º'),
+    ("ordmasculine", 'º'),
+    ("guillemotright", '»'),
+    ("guillemetright", '»'),
+    ("onequarter", '¼'),
+    ("onehalf", '½'),
+    ("threequarters", '¾'),
+    ("questiondown", '¿'),
+    ("Agrave", 'À'),
+    ("Aacute", 'Á'),
+    ("Acircumflex", 'Â'),
+    ("Atilde", 'Ã'),
+    ("Adiaeresis", 'Ä'),
+    ("Aring", 'Å'),
+    ("AE", 'Æ'),
+    ("Ccedilla", 'Ç'),
+    ("Egrave", 'È'),
+    ("Eacute", 'É'),
+    ("Ecircumflex", 'Ê'),
+    ("Ediaeresis", 'Ë'),
+    ("Igrave", 'Ì'),
+    ("Iacute", 'Í'),
+    ("Icircumflex", 'Î'),
+    ("Idiaeresis", 'Ï'),
+    ("ETH", 'Ð'),
+    ("Ntilde", 'Ñ'),
+    ("Ograve", 'Ò'),
+    ("Oacute", 'Ó'),
+    ("Ocircumflex", 'Ô'),
+    ("Otilde", 'Õ'),
+    ("Odiaeresis", 'Ö'),
+    ("multiply", '×'),
+    ("Oslash", 'Ø'),
+    ("Ooblique", 'Ø'),
+    ("Ugrave", 'Ù'),
+    ("Uacute", 'Ú'),
+    ("Ucircumflex", 'Û'),
+    ("Udiaeresis", 'Ü'),
+    ("Yacute", 'Ý'),
+    ("THORN", 'Þ'),
+    ("ssharp", 'ß'),
+    ("agrave", 'à'),
+    ("aacute", 'á'),
+    ("acircumflex", 'â'),
+    ("atilde", 'ã'),
+    ("adiaeresis", 'ä'),
+    ("aring", 'å'),
+    ("ae", 'æ'),
+    ("ccedilla", 'ç'),
+    ("egrave", 'è'),
+    ("eacute", 'é'),
+    ("ecircumflex", 'ê'),
+    ("ediaeresis", 'ë'),
+    ("igrave", 'ì'),
+    ("iacute", 'í'),
+    ("icircumflex", 'î'),
+    ("idiaeresis", 'ï'),
+    ("eth", 'ð'),
+    ("ntilde", 'ñ'),
+    ("ograve", 'ò'),
+    ("oacute", 'ó'),
+    ("ocircumflex", 'ô'),
+    ("otilde", 'õ'),
+    ("odiaeresis", 'ö'),
+    ("division", '÷'),
+    ("oslash", 'ø'),
+    ("ugrave", 'ù'),
+    ("uacute", 'ú'),
+    ("ucircumflex", 'û'),
+    ("udiaeresis", 'ü'),
+    ("yacute", 'ý'),
+    ("thorn", 'þ'),
+    ("ydiaeresis", 'ÿ'),
+    ("EuroSign", '€'),
+    ("oe", 'œ'),
+    ("OE", 'Œ'),
+    ("endash", '–'),
+    ("emdash", '—'),
+    ("ellipsis", '…'),
+    ("leftsinglequotemark", '‘'),
+    ("rightsinglequotemark", '’'),
+    ("leftdoublequotemark", '“'),
+    ("rightdoublequotemark", '”'),
+    ("singlelowquotemark", '‚'),
+    ("doublelowquotemark", '„'),
+    ("dagger", '†'),
+    ("doubledagger", '‡'),
+    ("trademark", '™'),
+];
+
+/// US QWERTY
+const US_SYMBOLS: &str = r#"
+    key <TLDE> { [ grave, asciitilde ] };
+    key <AE01> { [ 1, exclam ] };
+    key <AE02> { [ 2, at ] };
+    key <AE03> { [ 3, numbersign ] };
+    key <AE04> { [ 4, dollar ] };
+    key <AE05> { [ 5, percent ] };
+    key <AE06> { [ 6, asciicircum ] };
+    key <AE07> { [ 7, ampersand ] };
+    key <AE08> { [ 8, asterisk ] };
+    key <AE09> { [ 9, parenleft ] };
+    key <AE10> { [ 0, parenright ] };
+    key <AE11> { [ minus, underscore ] };
+    key <AE12> { [ equal, plus ] };
+    key <AD01> { [ q, Q ] };
+    key <AD02> { [ w, W ] };
+    key <AD03> { [ e, E ] };
+    key <AD04> { [ r, R ] };
+    key <AD05> { [ t, T ] };
+    key <AD06> { [ y, Y ] };
+    key <AD07> { [ u, U ] };
+    key <AD08> { [ i, I ] };
+    key <AD09> { [ o, O ] };
+    key <AD10> { [ p, P ] };
+    key <AD11> { [ bracketleft, braceleft ] };
+    key <AD12> { [ bracketright, braceright ] };
+    key <AC01> { [ a, A ] };
+    key <AC02> { [ s, S ] };
+    key <AC03> { [ d, D ] };
+    key <AC04> { [ f, F ] };
+    key <AC05> { [ g, G ] };
+    key <AC06> { [ h, H ] };
+    key <AC07> { [ j, J ] };
+    key <AC08> { [ k, K ] };
+    key <AC09> { [ l, L ] };
+    key <AC10> { [ semicolon, colon ] };
+    key <AC11> { [ apostrophe, quotedbl ] };
+    key <BKSL> { [ backslash, bar ] };
+    key <AB01> { [ z, Z ] };
+    key <AB02> { [ x, X ] };
+    key <AB03> { [ c, C ] };
+    key <AB04> { [ v, V ] };
+    key <AB05> { [ b, B ] };
+    key <AB06> { [ n, N ] };
+    key <AB07> { [ m, M ] };
+    key <AB08> { [ comma, less ] };
+    key <AB09> { [ period, greater ] };
+    key <AB10> { [ slash, question ] };
+"#;
+
+/// UK QWERTY
+const GB_SYMBOLS: &str = r#"
+    key <TLDE> { [ grave, notsign, bar, bar ] };
+    key <AE01> { [ 1, exclam ] };
+    key <AE02> { [ 2, quotedbl, twosuperior ] };
+    key <AE03> { [ 3, sterling, threesuperior ] };
+    key <AE04> { [ 4, dollar, EuroSign ] };
+    key <AE05> { [ 5, percent ] };
+    key <AE06> { [ 6, asciicircum ] };
+    key <AE07> { [ 7, ampersand ] };
+    key <AE08> { [ 8, asterisk ] };
+    key <AE09> { [ 9, parenleft ] };
+    key <AE10> { [ 0, parenright ] };
+    key <AE11> { [ minus, underscore ] };
+    key <AE12> { [ equal, plus ] };
+    key <AD01> { [ q, Q ] };
+    key <AD02> { [ w, W ] };
+    key <AD03> { [ e, E, eacute, Eacute ] };
+    key <AD04> { [ r, R ] };
+    key <AD05> { [ t, T ] };
+    key <AD06> { [ y, Y ] };
+    key <AD07> { [ u, U, uacute, Uacute ] };
+    key <AD08> { [ i, I, iacute, Iacute ] };
+    key <AD09> { [ o, O, oacute, Oacute ] };
+    key <AD10> { [ p, P ] };
+    key <AD11> { [ bracketleft, braceleft ] };
+    key <AD12> { [ bracketright, braceright ] };
+    key <AC01> { [ a, A, aacute, Aacute ] };
+    key <AC02> { [ s, S ] };
+    key <AC03> { [ d, D ] };
+    key <AC04> { [ f, F ] };
+    key <AC05> { [ g, G ] };
+    key <AC06> { [ h, H ] };
+    key <AC07> { [ j, J ] };
+    key <AC08> { [ k, K ] };
+    key <AC09> { [ l, L ] };
+    key <AC10> { [ semicolon, colon ] };
+    key <AC11> { [ apostrophe, at ] };
+    key <BKSL> { [ numbersign, asciitilde ] };
+    key <LSGT> { [ backslash, bar ] };
+    key <AB01> { [ z, Z ] };
+    key <AB02> { [ x, X ] };
+    key <AB03> { [ c, C ] };
+    key <AB04> { [ v, V ] };
+    key <AB05> { [ b, B ] };
+    key <AB06> { [ n, N ] };
+    key <AB07> { [ m, M ] };
+    key <AB08> { [ comma, less ] };
+    key <AB09> { [ period, greater ] };
+    key <AB10> { [ slash, question ] };
+"#;
+
+/// German QWERTZ (dead keys are not typeable)
+const DE_SYMBOLS: &str = r#"
+    key <TLDE> { [ dead_circumflex, degree ] };
+    key <AE01> { [ 1, exclam, onesuperior ] };
+    key <AE02> { [ 2, quotedbl, twosuperior ] };
+    key <AE03> { [ 3, section, threesuperior ] };
+    key <AE04> { [ 4, dollar, onequarter ] };
+    key <AE05> { [ 5, percent, onehalf ] };
+    key <AE06> { [ 6, ampersand, notsign ] };
+    key <AE07> { [ 7, slash, braceleft ] };
+    key <AE08> { [ 8, parenleft, bracketleft ] };
+    key <AE09> { [ 9, parenright, bracketright ] };
+    key <AE10> { [ 0, equal, braceright ] };
+    key <AE11> { [ ssharp, question, backslash ] };
+    key <AE12> { [ dead_acute, dead_grave ] };
+    key <AD01> { [ q, Q, at ] };
+    key <AD02> { [ w, W ] };
+    key <AD03> { [ e, E, EuroSign ] };
+    key <AD04> { [ r, R ] };
+    key <AD05> { [ t, T ] };
+    key <AD06> { [ z, Z ] };
+    key <AD07> { [ u, U ] };
+    key <AD08> { [ i, I ] };
+    key <AD09> { [ o, O ] };
+    key <AD10> { [ p, P ] };
+    key <AD11> { [ udiaeresis, Udiaeresis ] };
+    key <AD12> { [ plus, asterisk, asciitilde ] };
+    key <AC01> { [ a, A ] };
+    key <AC02> { [ s, S ] };
+    key <AC03> { [ d, D ] };
+    key <AC04> { [ f, F ] };
+    key <AC05> { [ g, G ] };
+    key <AC06> { [ h, H ] };
+    key <AC07> { [ j, J ] };
+    key <AC08> { [ k, K ] };
+    key <AC09> { [ l, L ] };
+    key <AC10> { [ odiaeresis, Odiaeresis ] };
+    key <AC11> { [ adiaeresis, Adiaeresis ] };
+    key <BKSL> { [ numbersign, apostrophe ] };
+    key <LSGT> { [ less, greater, bar ] };
+    key <AB01> { [ y, Y ] };
+    key <AB02> { [ x, X ] };
+    key <AB03> { [ c, C ] };
+    key <AB04> { [ v, V ] };
+    key <AB05> { [ b, B ] };
+    key <AB06> { [ n, N ] };
+    key <AB07> { [ m, M, mu ] };
+    key <AB08> { [ comma, semicolon ] };
+    key <AB09> { [ period, colon ] };
+    key <AB10> { [ minus, underscore ] };
+"#;
+
+/// French AZERTY (dead keys are not typeable)
+const FR_SYMBOLS: &str = r#"
+    key <TLDE> { [ twosuperior ] };
+    key <AE01> { [ ampersand, 1 ] };
+    key <AE02> { [ eacute, 2, asciitilde ] };
+    key <AE03> { [ quotedbl, 3, numbersign ] };
+    key <AE04> { [ apostrophe, 4, braceleft ] };
+    key <AE05> { [ parenleft, 5, bracketleft ] };
+    key <AE06> { [ minus, 6, bar ] };
+    key <AE07> { [ egrave, 7, grave ] };
+    key <AE08> { [ underscore, 8, backslash ] };
+    key <AE09> { [ ccedilla, 9, asciicircum ] };
+    key <AE10> { [ agrave, 0, at ] };
+    key <AE11> { [ parenright, degree, bracketright ] };
+    key <AE12> { [ equal, plus, braceright ] };
+    key <AD01> { [ a, A ] };
+    key <AD02> { [ z, Z ] };
+    key <AD03> { [ e, E, EuroSign ] };
+    key <AD04> { [ r, R ] };
+    key <AD05> { [ t, T ] };
+    key <AD06> { [ y, Y ] };
+    key <AD07> { [ u, U ] };
+    key <AD08> { [ i, I ] };
+    key <AD09> { [ o, O ] };
+    key <AD10> { [ p, P ] };
+    key <AD11> { [ dead_circumflex, dead_diaeresis ] };
+    key <AD12> { [ dollar, sterling, currency ] };
+    key <AC01> { [ q, Q ] };
+    key <AC02> { [ s, S ] };
+    key <AC03> { [ d, D ] };
+    key <AC04> { [ f, F ] };
+    key <AC05> { [ g, G ] };
+    key <AC06> { [ h, H ] };
+    key <AC07> { [ j, J ] };
+    key <AC08> { [ k, K ] };
+    key <AC09> { [ l, L ] };
+    key <AC10> { [ m, M ] };
+    key <AC11> { [ ugrave, percent ] };
+    key <BKSL> { [ asterisk, mu ] };
+    key <LSGT> { [ less, greater ] };
+    key <AB01> { [ w, W ] };
+    key <AB02> { [ x, X ] };
+    key <AB03> { [ c, C ] };
+    key <AB04> { [ v, V ] };
+    key <AB05> { [ b, B ] };
+    key <AB06> { [ n, N ] };
+    key <AB07> { [ comma, question ] };
+    key <AB08> { [ semicolon, period ] };
+    key <AB09> { [ colon, slash ] };
+    key <AB10> { [ exclam, section ] };
+"#;
+
+/// Compose key input for a character (from the default X11/libX11 Compose table)
+pub fn compose_sequence(c: char) -> Option<&'static str> {
+    COMPOSE_SEQUENCES
+        .iter()
+        .find(|(ch, _)| *ch == c)
+        .map(|&(_, seq)| seq)
+}
+
+/// Common Compose sequences, typed after the Compose key
+#[rustfmt::skip]
+const COMPOSE_SEQUENCES: &[(char, &str)] = &[
+    ('á', "'a"), ('é', "'e"), ('í', "'i"), ('ó', "'o"), ('ú', "'u"), ('ý', "'y"),
+    ('Á', "'A"), ('É', "'E"), ('Í', "'I"), ('Ó', "'O"), ('Ú', "'U"), ('Ý', "'Y"),
+    ('à', "`a"), ('è', "`e"), ('ì', "`i"), ('ò', "`o"), ('ù', "`u"),
+    ('À', "`A"), ('È', "`E"), ('Ì', "`I"), ('Ò', "`O"), ('Ù', "`U"),
+    ('â', "^a"), ('ê', "^e"), ('î', "^i"), ('ô', "^o"), ('û', "^u"),
+    ('Â', "^A"), ('Ê', "^E"), ('Î', "^I"), ('Ô', "^O"), ('Û', "^U"),
+    ('ä', "\"a"), ('ë', "\"e"), ('ï', "\"i"), ('ö', "\"o"), ('ü', "\"u"), ('ÿ', "\"y"),
+    ('Ä', "\"A"), ('Ë', "\"E"), ('Ï', "\"I"), ('Ö', "\"O"), ('Ü', "\"U"),
+    ('ã', "~a"), ('ñ', "~n"), ('õ', "~o"), ('Ã', "~A"), ('Ñ', "~N"), ('Õ', "~O"),
+    ('ç', ",c"), ('Ç', ",C"), ('å', "oa"), ('Å', "oA"), ('ø', "/o"), ('Ø', "/O"),
+    ('æ', "ae"), ('Æ', "AE"), ('œ', "oe"), ('Œ', "OE"), ('ß', "ss"),
+    ('€', "=e"), ('£', "-L"), ('¥', "=Y"), ('¢', "|c"),
+    ('©', "oc"), ('®', "or"), ('™', "tm"), ('°', "oo"), ('±', "+-"),
+    ('½', "12"), ('¼', "14"), ('¾', "34"), ('²', "^2"), ('³', "^3"),
+    ('«', "<<"), ('»', ">>"), ('¡', "!!"), ('¿', "??"), ('§', "so"), ('¶', "p!"),
+    ('—', "---"), ('–', "--."), ('…', ".."), ('·', ".-"), ('×', "xx"), ('÷', ":-"),
+    ('‘', "<'"), ('’', ">'"), ('“', "<\""), ('”', ">\""),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stroke(key: u16, shift: bool, altgr: bool) -> Option<KeyStroke> {
+        Some(KeyStroke { key, shift, altgr })
+    }
+
+    #[test]
+    fn test_us_layout() {
+        let us = Layout::us();
+        assert_eq!(us.lookup('a'), stroke(keys::KEY_A, false, false));
+        assert_eq!(us.lookup('b'), stroke(keys::KEY_B, false, false));
+        assert_eq!(us.lookup('Q'), stroke(keys::KEY_Q, true, false));
+        assert_eq!(us.lookup('@'), stroke(keys::KEY_2, true, false));
+        assert_eq!(us.lookup('\n'), stroke(keys::KEY_ENTER, false, false));
+        assert_eq!(us.lookup('é'), None);
+    }
+
+    #[test]
+    fn test_builtin_layouts() {
+        let gb = Layout::builtin("uk").unwrap();
+        assert_eq!(gb.lookup('@'), stroke(keys::KEY_APOSTROPHE, true, false));
+        assert_eq!(gb.lookup('£'), stroke(keys::KEY_3, true, false));
+        assert_eq!(gb.lookup('€'), stroke(keys::KEY_4, false, true));
+
+        let de = Layout::builtin("de").unwrap();
+        assert_eq!(de.lookup('z'), stroke(keys::KEY_Y, false, false));
+        assert_eq!(de.lookup('@'), stroke(keys::KEY_Q, false, true));
+        assert_eq!(de.lookup('ß'), stroke(keys::KEY_MINUS, false, false));
+        assert_eq!(de.lookup('^'), None);
+
+        let fr = Layout::builtin("fr").unwrap();
+        assert_eq!(fr.lookup('a'), stroke(keys::KEY_Q, false, false));
+        assert_eq!(fr.lookup('1'), stroke(keys::KEY_1, true, false));
+        assert_eq!(fr.lookup('m'), stroke(keys::KEY_SEMICOLON, false, false));
+    }
+
+    #[test]
+    fn test_parse_xkbcomp_output() {
+        let keymap = r#"
+            xkb_symbols "pc+us+inet(evdev)" {
+                key <ESC>  { [ Escape ] };
+                key <AE01> { [ 1, exclam, onesuperior, exclamdown ] };
+                key <AC01> {
+                    type= "FOUR_LEVEL_SEMIALPHABETIC",
+                    symbols[Group1]= [ a, A, ae, AE ],
+                    symbols[Group2]= [ Cyrillic_ef, Cyrillic_EF ]
+                };
+                key <AD03> { [ e, E, U20AC, 0x1002014 ] };
+            };
+        "#;
+        let layout = Layout::parse_xkb("test", keymap).unwrap();
+        assert_eq!(layout.lookup('æ'), stroke(keys::KEY_A, false, true));
+        assert_eq!(layout.lookup('Æ'), stroke(keys::KEY_A, true, true));
+        assert_eq!(layout.lookup('¡'), stroke(keys::KEY_1, true, true));
+        assert_eq!(layout.lookup('€'), stroke(keys::KEY_E, false, true));
+        assert_eq!(layout.lookup('—'), stroke(keys::KEY_E, true, true));
+        assert_eq!(layout.lookup('ф'), None);
+    }
+
+    #[test]
+    fn test_compose_sequence() {
+        assert_eq!(compose_sequence('é'), Some("'e"));
+        assert_eq!(compose_sequence('—'), Some("---"));
+        assert_eq!(compose_sequence('😀'), None);
+    }
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(Layout::from_name("").unwrap().name(), "us");
+        assert_eq!(Layout::from_name("en").unwrap().name(), "us");
+        assert!(Layout::from_name("klingon").is_err());
+        assert!(Layout::from_name("/nonexistent/layout.xkb").is_err());
+    }
+}
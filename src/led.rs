@@ -3,9 +3,10 @@
 //! Handles all LED operations asynchronously to avoid blocking the main event loop
 //! and to properly manage flashing patterns.
 
-use std::fs::OpenOptions;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::Arc;
@@ -14,11 +15,19 @@ use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
 
+use crate::config::{Brightness, OpenRgbConfig, TimeOfDay};
+use crate::effects::{self, Effect, EffectRenderer};
 use crate::events;
+use crate::keymap;
+use crate::openrgb;
 
 /// Commands that can be sent to the LED controller thread
 #[derive(Debug)]
 pub enum LedCommand {
+    /// Open a (new) keyboard device and replay the LED state onto it
+    Connect(PathBuf),
+    /// Stop writing to the keyboard until the next `Connect`
+    Disconnect,
     /// Set MR LED on or off
     SetMrLed(bool),
     /// Set profile LED (M1, M2, or M3)
@@ -35,8 +44,56 @@ pub enum LedCommand {
     QuickFlashMr { count: u8 },
     /// Set entire keyboard to a single color
     SetFullKeyboardColor { r: u8, g: u8, b: u8 },
-    /// Restore G-keys to configured color (or turn off if None)
+    /// The lighting is being applied again: resend the keys set next even
+    /// if unchanged, in case something else changed them
+    Reapply,
+    /// Restore G-keys to configured color (if None, the colors saved from
+    /// OpenRGB before the feedback, else off)
     RestoreGKeysColor { color: Option<(u8, u8, u8)> },
+    /// Set individual keys, as (LED address, color) pairs
+    SetKeyColors { colors: Vec<(u8, (u8, u8, u8))> },
+    /// Set each G-key (G1-G5) to its own color
+    SetGKeyColors { colors: [(u8, u8, u8); 5] },
+    /// Start pulsing a G-key while its macro runs
+    MacroStarted { gkey: u8 },
+    /// Stop pulsing a G-key, flash it (unless `flash` is None), then set it
+    /// to `restore` (as for `RestoreGKeysColor`)
+    MacroFinished {
+        gkey: u8,
+        flash: Option<(u8, u8, u8)>,
+        restore: Option<(u8, u8, u8)>,
+    },
+    /// Cooperate with OpenRGB for G-key feedback, or stop if None
+    SetOpenRgb(Option<OpenRgbConfig>),
+    /// Scale, schedule and idle blanking for key colors (None for full brightness)
+    SetBrightness(Option<Brightness>),
+    /// A G-key or M-key was pressed, which wakes idle-blanked lighting and
+    /// may end standby
+    Activity,
+    /// Show a status indicator color on a key over its usual color, or
+    /// give the key back if None
+    SetIndicator {
+        address: u8,
+        color: Option<(u8, u8, u8)>,
+    },
+    /// Give every indicator key back its usual color
+    ClearIndicators,
+    /// Run an effect over `base` key colors, pausing while a `yield_to`
+    /// process is running
+    StartEffect {
+        effect: Effect,
+        fps: u32,
+        base: Vec<(u8, (u8, u8, u8))>,
+        yield_to: Vec<String>,
+    },
+    /// Stop the running effect, leaving the keys on its last frame
+    StopEffect,
+    /// Set the keys a running effect draws to one color, unless it is
+    /// paused for an external tool
+    ResetEffectKeys { r: u8, g: u8, b: u8 },
+    /// A key was pressed on the main keyboard (evdev key code), for reactive
+    /// effects, idle blanking and noticing the end of standby
+    KeyPressed { code: u16 },
     /// Shutdown the LED controller thread
     Shutdown,
 }
@@ -47,6 +104,37 @@ const MR_FLASH_INTERVAL: Duration = Duration::from_millis(500);
 /// MR LED quick flash interval for success (125ms on, 125ms off)
 const MR_QUICK_FLASH_INTERVAL: Duration = Duration::from_millis(125);
 
+/// Frame interval for G-key macro feedback
+const FEEDBACK_FRAME_INTERVAL: Duration = Duration::from_millis(40);
+
+/// One full pulse (dim - bright - dim) while a macro runs
+const PULSE_PERIOD: Duration = Duration::from_millis(1200);
+
+/// Color of the pulse while a macro runs
+const PULSE_COLOR: (u8, u8, u8) = (255, 255, 255);
+
+/// Result flash interval (on/off) when a macro finishes
+const RESULT_FLASH_INTERVAL: Duration = Duration::from_millis(150);
+
+/// Number of result flashes when a macro finishes
+const RESULT_FLASH_COUNT: u32 = 3;
+
+/// How often to check whether the system resumed from suspend
+const RESUME_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Suspended time that counts as a suspend, above clock jitter
+const RESUME_THRESHOLD: Duration = Duration::from_secs(1);
+
+/// Time without key presses after which the keyboard may have gone into
+/// standby, where it drops the LED state when it wakes
+const STANDBY_IDLE: Duration = Duration::from_secs(60);
+
+/// Time for the keyboard to finish waking before its LEDs are restored
+const WAKE_SETTLE: Duration = Duration::from_millis(500);
+
+/// How often to check whether an external lighting tool is running
+const EXTERNAL_TOOL_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
 /// Time window (ms) to ignore MR events after LED write
 const MR_LED_DEBOUNCE_MS: u64 = 30;
 
@@ -61,22 +149,20 @@ pub struct LedController {
 
 impl LedController {
     /// Create a new LED controller with its own thread
-    pub fn new(device_path: PathBuf) -> Result<Self> {
+    ///
+    /// The controller keeps the desired LED state while no keyboard is
+    /// connected and writes it out on `connect`.
+    pub fn new() -> Result<Self> {
         let (tx, rx) = mpsc::channel();
         let mr_led_write_time = Arc::new(AtomicU64::new(0));
         let mr_time_clone = mr_led_write_time.clone();
 
-        let path_clone = device_path.clone();
         let thread = thread::Builder::new()
             .name("led-controller".into())
-            .spawn(move || {
-                if let Err(e) = led_worker(path_clone, rx, mr_time_clone) {
-                    log::error!("LED worker error: {}", e);
-                }
-            })
+            .spawn(move || led_worker(rx, mr_time_clone))
             .context("Failed to spawn LED controller thread")?;
 
-        log::debug!("LED controller started for {}", device_path.display());
+        log::debug!("LED controller started");
 
         Ok(Self {
             tx,
@@ -109,6 +195,16 @@ impl LedController {
         }
     }
 
+    /// Write to a newly opened keyboard, replaying the current LED state
+    pub fn connect(&self, device_path: PathBuf) {
+        self.send(LedCommand::Connect(device_path));
+    }
+
+    /// Stop writing to a keyboard that went away
+    pub fn disconnect(&self) {
+        self.send(LedCommand::Disconnect);
+    }
+
     /// Set MR LED on or off
     pub fn set_mr_led(&self, on: bool) {
         self.send(LedCommand::SetMrLed(on));
@@ -149,11 +245,112 @@ impl LedController {
         self.send(LedCommand::SetFullKeyboardColor { r, g, b });
     }
 
-    /// Restore G-keys to configured color (or turn off if None)
+    /// Resend the key colors set next even if unchanged, when applying the
+    /// configured lighting again
+    pub fn reapply(&self) {
+        self.send(LedCommand::Reapply);
+    }
+
+    /// Restore G-keys to configured color (or what they were before the
+    /// feedback if None)
     pub fn restore_gkeys_color(&self, color: Option<(u8, u8, u8)>) {
         self.send(LedCommand::RestoreGKeysColor { color });
     }
 
+    /// Set individual keys, as (LED address, color) pairs
+    pub fn set_key_colors(&self, colors: Vec<(u8, (u8, u8, u8))>) {
+        self.send(LedCommand::SetKeyColors { colors });
+    }
+
+    /// Set each G-key (G1-G5) to its own color
+    pub fn set_gkey_colors(&self, colors: [(u8, u8, u8); 5]) {
+        self.send(LedCommand::SetGKeyColors { colors });
+    }
+
+    /// Start pulsing a G-key while its macro runs
+    pub fn macro_started(&self, gkey: u8) {
+        self.send(LedCommand::MacroStarted { gkey });
+    }
+
+    /// Stop pulsing a G-key, flash it in `flash`, then set it to `restore`
+    /// (or what it was before the feedback if None)
+    pub fn macro_finished(
+        &self,
+        gkey: u8,
+        flash: Option<(u8, u8, u8)>,
+        restore: Option<(u8, u8, u8)>,
+    ) {
+        self.send(LedCommand::MacroFinished {
+            gkey,
+            flash,
+            restore,
+        });
+    }
+
+    /// Cooperate with OpenRGB for G-key feedback (None to stop)
+    pub fn set_openrgb(&self, config: Option<OpenRgbConfig>) {
+        self.send(LedCommand::SetOpenRgb(config));
+    }
+
+    /// Set the brightness scale, schedule and idle blanking
+    pub fn set_brightness(&self, brightness: Option<Brightness>) {
+        self.send(LedCommand::SetBrightness(brightness));
+    }
+
+    /// Note a key press that isn't seen by `key_sink`, for idle blanking
+    pub fn activity(&self) {
+        self.send(LedCommand::Activity);
+    }
+
+    /// Give every status indicator key back its usual color
+    pub fn clear_indicators(&self) {
+        self.send(LedCommand::ClearIndicators);
+    }
+
+    /// Run a lighting effect over `base` key colors
+    pub fn start_effect(
+        &self,
+        effect: Effect,
+        fps: u32,
+        base: Vec<(u8, (u8, u8, u8))>,
+        yield_to: Vec<String>,
+    ) {
+        self.send(LedCommand::StartEffect {
+            effect,
+            fps,
+            base,
+            yield_to,
+        });
+    }
+
+    /// Stop the running lighting effect, if any
+    pub fn stop_effect(&self) {
+        self.send(LedCommand::StopEffect);
+    }
+
+    /// Set the keys the running effect draws to one color, so they aren't
+    /// left on its last frame. Does nothing without a drawing effect.
+    pub fn reset_effect_keys(&self, r: u8, g: u8, b: u8) {
+        self.send(LedCommand::ResetEffectKeys { r, g, b });
+    }
+
+    /// A callback passing key presses (evdev codes) to reactive effects and
+    /// idle blanking
+    pub fn key_sink(&self) -> impl Fn(u16) + Send + 'static {
+        let tx = self.tx.clone();
+        move |code| {
+            // Fails only once the LED thread is gone
+            let _ = tx.send(LedCommand::KeyPressed { code });
+        }
+    }
+
+    /// A callback setting status indicator colors, as for `SetIndicator`
+    pub fn indicator_sink(&self) -> impl Fn(u8, Option<(u8, u8, u8)>) + Clone + Send + 'static {
+        let tx = self.tx.clone();
+        move |address, color| {
+            let _ = tx.send(LedCommand::SetIndicator { address, color });
+        }
+    }
 }
 
 impl Drop for LedController {
@@ -173,122 +370,707 @@ fn current_time_ms() -> u64 {
         .unwrap_or(0)
 }
 
-/// LED worker thread function
-fn led_worker(
-    device_path: PathBuf,
-    rx: Receiver<LedCommand>,
+/// What a G-key shows for macro feedback
+#[derive(Debug, Clone, Copy)]
+enum Feedback {
+    /// Macro running: pulse
+    Pulsing { since: Instant },
+    /// Macro done: flash `count` times, then show `restore`
+    Flashing {
+        since: Instant,
+        color: (u8, u8, u8),
+        count: u32,
+        restore: (u8, u8, u8),
+    },
+}
+
+/// Feedback on one G-key and the color last written to it
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    feedback: Feedback,
+    last: Option<(u8, u8, u8)>,
+}
+
+/// Macro feedback state of the five G-keys
+#[derive(Default)]
+struct GKeyFeedback {
+    slots: [Option<Slot>; 5],
+}
+
+impl GKeyFeedback {
+    fn set(&mut self, gkey: u8, feedback: Feedback) {
+        let index = usize::from(gkey).wrapping_sub(1);
+        if let Some(slot) = self.slots.get_mut(index) {
+            *slot = Some(Slot {
+                feedback,
+                last: None,
+            });
+        }
+    }
+
+    /// Feedback showing on a G-key, if any
+    fn get(&self, gkey: u8) -> Option<Feedback> {
+        let index = usize::from(gkey).wrapping_sub(1);
+        self.slots.get(index)?.map(|slot| slot.feedback)
+    }
+
+    fn start(&mut self, gkey: u8, now: Instant) {
+        self.set(gkey, Feedback::Pulsing { since: now });
+    }
+
+    fn finish(
+        &mut self,
+        gkey: u8,
+        flash: Option<(u8, u8, u8)>,
+        restore: (u8, u8, u8),
+        now: Instant,
+    ) {
+        let count = if flash.is_some() {
+            RESULT_FLASH_COUNT
+        } else {
+            0
+        };
+        self.set(
+            gkey,
+            Feedback::Flashing {
+                since: now,
+                color: flash.unwrap_or(restore),
+                count,
+                restore,
+            },
+        );
+    }
+
+    fn is_active(&self) -> bool {
+        self.slots.iter().any(Option::is_some)
+    }
+
+    /// Advance to `now`, returning the G-key colors that changed
+    fn frame(&mut self, now: Instant) -> Vec<(u8, (u8, u8, u8))> {
+        let mut changed = Vec::new();
+        for (gkey, slot) in (1..=5u8).zip(&mut self.slots) {
+            let Some(Slot { feedback, last }) = slot else {
+                continue;
+            };
+            let (color, done) = match *feedback {
+                Feedback::Pulsing { since } => (pulse_color(now - since), false),
+                Feedback::Flashing {
+                    since,
+                    color,
+                    count,
+                    restore,
+                } => {
+                    let phase =
+                        ((now - since).as_millis() / RESULT_FLASH_INTERVAL.as_millis()) as u32;
+                    if phase >= count * 2 {
+                        (restore, true)
+                    } else if phase % 2 == 0 {
+                        (color, false)
+                    } else {
+                        ((0, 0, 0), false)
+                    }
+                }
+            };
+            if *last != Some(color) {
+                changed.push((gkey, color));
+                *last = Some(color);
+            }
+            if done {
+                *slot = None;
+            }
+        }
+        changed
+    }
+}
+
+/// Pulse color `elapsed` into a pulse: a triangle wave between 10% and 100%
+fn pulse_color(elapsed: Duration) -> (u8, u8, u8) {
+    let period = PULSE_PERIOD.as_millis();
+    let t = elapsed.as_millis() % period;
+    // 0 -> 1000 -> 0 over one period
+    let level = 1000 - (2000 * t / period).abs_diff(1000);
+    let scale = |c: u8| (u128::from(c) * (100 + level * 9 / 10) / 1000) as u8;
+    (
+        scale(PULSE_COLOR.0),
+        scale(PULSE_COLOR.1),
+        scale(PULSE_COLOR.2),
+    )
+}
+
+/// Desired key colors, and the colors last committed to the keyboard so
+/// unchanged keys aren't resent
+#[derive(Default)]
+struct KeyState {
+    desired: BTreeMap<u8, (u8, u8, u8)>,
+    /// Status indicator colors, shown over the desired colors
+    indicators: BTreeMap<u8, (u8, u8, u8)>,
+    committed: HashMap<u8, (u8, u8, u8)>,
+    /// Whether direct mode is set up; only needed for keys other than G-keys
+    direct_mode: bool,
+}
+
+impl KeyState {
+    /// Update the desired colors from (LED address, color) pairs
+    fn set(&mut self, colors: &[(u8, (u8, u8, u8))]) {
+        self.desired.extend(colors.iter().copied());
+    }
+
+    /// Show an indicator color on a key, or its desired color again if None
+    fn set_indicator(&mut self, address: u8, color: Option<(u8, u8, u8)>) {
+        match color {
+            Some(color) => {
+                self.indicators.insert(address, color);
+            }
+            None => {
+                if self.indicators.remove(&address).is_some() {
+                    // Keys nothing else colors go off
+                    self.desired.entry(address).or_insert((0, 0, 0));
+                }
+            }
+        }
+    }
+
+    /// The colors to show: indicators over the desired colors
+    fn shown(&self) -> impl Iterator<Item = (u8, (u8, u8, u8))> + '_ {
+        let desired = self
+            .desired
+            .iter()
+            .map(|(&address, &color)| (address, *self.indicators.get(&address).unwrap_or(&color)));
+        let only_indicators = self
+            .indicators
+            .iter()
+            .filter(|(address, _)| !self.desired.contains_key(address))
+            .map(|(&address, &color)| (address, color));
+        desired.chain(only_indicators)
+    }
+
+    /// The shown colors at `level` percent brightness that differ from the
+    /// committed ones, which are then taken as committed
+    fn changes(&mut self, level: u8) -> Vec<(u8, (u8, u8, u8))> {
+        let changed: Vec<_> = self
+            .shown()
+            .map(|(address, color)| (address, dim(color, level)))
+            .filter(|(address, color)| self.committed.get(address) != Some(color))
+            .collect();
+        self.committed.extend(changed.iter().copied());
+        changed
+    }
+
+    /// Reports that set `changed` keys, grouped by color into multi-key
+    /// frames and followed by a commit. Empty if nothing changed.
+    fn reports(&mut self, changed: &[(u8, (u8, u8, u8))]) -> Vec<[u8; 20]> {
+        if changed.is_empty() {
+            return Vec::new();
+        }
+
+        let mut reports = Vec::new();
+        // G-keys can be set without direct mode, so G-key-only changes leave
+        // the rest of the keyboard's effect alone
+        let direct = changed
+            .iter()
+            .any(|&(address, _)| keymap::gkey_at(address).is_none());
+        if direct && !self.direct_mode {
+            reports.extend(events::direct_mode_init_commands());
+            self.direct_mode = true;
+        }
+        reports.extend(events::multi_key_commands(changed));
+        reports.push(events::led_commit_command());
+        reports
+    }
+
+    /// Forget the committed state, after something else may have changed it
+    /// or the keyboard was reopened, so the desired colors are all resent
+    fn invalidate(&mut self) {
+        self.committed.clear();
+        self.direct_mode = false;
+    }
+}
+
+/// Scale a color to `level` percent
+fn dim((r, g, b): (u8, u8, u8), level: u8) -> (u8, u8, u8) {
+    let scale = |c: u8| (u16::from(c) * u16::from(level.min(100)) / 100) as u8;
+    (scale(r), scale(g), scale(b))
+}
+
+/// Brightness level from the config, the time of day and idleness
+struct Dimmer {
+    config: Option<Brightness>,
+    last_activity: Instant,
+}
+
+impl Dimmer {
+    /// Level in percent at `time` of day and instant `now`
+    fn level(&self, time: TimeOfDay, now: Instant) -> u8 {
+        let Some(ref config) = self.config else {
+            return 100;
+        };
+        if self.idle_deadline().is_some_and(|deadline| now >= deadline) {
+            return 0;
+        }
+        config.level_at(time)
+    }
+
+    /// Note a key press at `now`, returning true if it ends a pause long
+    /// enough for the keyboard to have gone into standby
+    fn key_pressed(&mut self, now: Instant) -> bool {
+        let woke = now.saturating_duration_since(self.last_activity) >= STANDBY_IDLE;
+        self.last_activity = now;
+        woke
+    }
+
+    /// When the lighting blanks unless a key is pressed
+    fn idle_deadline(&self) -> Option<Instant> {
+        let secs = self.config.as_ref()?.idle_secs?;
+        Some(self.last_activity + Duration::from_secs(secs))
+    }
+}
+
+/// The local time of day
+fn local_time() -> TimeOfDay {
+    // SAFETY: localtime_r only writes to the tm passed in
+    let tm = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        tm
+    };
+    TimeOfDay((tm.tm_hour * 60 + tm.tm_min) as u16)
+}
+
+/// G-key numbers
+const ALL_GKEYS: [u8; 5] = [1, 2, 3, 4, 5];
+
+/// (LED address, color) pairs for G1-G5
+fn gkey_colors(colors: [(u8, u8, u8); 5]) -> Vec<(u8, (u8, u8, u8))> {
+    (1..=5u8).map(keymap::gkey_address).zip(colors).collect()
+}
+
+/// A lighting effect being drawn by the LED thread
+struct RunningEffect {
+    renderer: EffectRenderer,
+    frame_interval: Duration,
+    last_frame: Instant,
+    /// Processes that take over the LEDs
+    yield_to: Vec<String>,
+    /// External tool the effect is paused for
+    paused_for: Option<String>,
+    last_check: Instant,
+}
+
+impl RunningEffect {
+    /// Time until the effect next needs the thread
+    fn timeout(&self) -> Duration {
+        let check = EXTERNAL_TOOL_CHECK_INTERVAL.saturating_sub(self.last_check.elapsed());
+        if self.paused_for.is_some() {
+            check
+        } else {
+            check.min(
+                self.frame_interval
+                    .saturating_sub(self.last_frame.elapsed()),
+            )
+        }
+    }
+
+    /// Pause while an external tool is running, returning true when the
+    /// effect has just resumed (or started) and needs direct mode again
+    fn check_external_tool(&mut self) -> bool {
+        self.last_check = Instant::now();
+        let tool = effects::external_tool_running(&self.yield_to);
+        let resumed = match (&tool, &self.paused_for) {
+            (Some(tool), None) => {
+                log::info!("{} is running, pausing the lighting effect", tool);
+                false
+            }
+            (None, Some(tool)) => {
+                log::info!("{} exited, resuming the lighting effect", tool);
+                true
+            }
+            _ => false,
+        };
+        self.paused_for = tool;
+        resumed
+    }
+}
+
+/// The LED state the keyboard should show, and where reports go
+struct Output {
+    /// None while no keyboard is connected
+    file: Option<File>,
+    keys: KeyState,
+    /// Brightness in percent applied to the key colors
+    level: u8,
+    profile_led: Option<u8>,
+    mr_led: bool,
     mr_led_write_time: Arc<AtomicU64>,
-) -> Result<()> {
-    // Open device for writing (separate handle from the reader)
-    let mut file = OpenOptions::new()
-        .write(true)
-        .open(&device_path)
-        .with_context(|| format!("LED worker failed to open {}", device_path.display()))?;
+    /// Set when cooperating with OpenRGB
+    openrgb: Option<openrgb::Link>,
+}
 
-    log::debug!("LED worker opened {}", device_path.display());
+impl Output {
+    fn new(mr_led_write_time: Arc<AtomicU64>) -> Self {
+        Self {
+            file: None,
+            keys: KeyState::default(),
+            level: 100,
+            profile_led: None,
+            mr_led: false,
+            mr_led_write_time,
+            openrgb: None,
+        }
+    }
+
+    /// Write a HID report to the device, logging errors
+    fn write(&mut self, report: &[u8; 20]) {
+        let Some(ref mut file) = self.file else {
+            return;
+        };
+        if let Err(e) = file.write_all(report) {
+            log::warn!("Failed to write LED report: {}", e);
+        }
+    }
+
+    /// Open a keyboard and replay the LED state onto it
+    fn connect(&mut self, device_path: &Path, keys: bool) {
+        match OpenOptions::new().write(true).open(device_path) {
+            Ok(file) => {
+                log::debug!("LED worker opened {}", device_path.display());
+                self.file = Some(file);
+                self.replay(keys);
+            }
+            Err(e) => {
+                log::warn!("LED worker failed to open {}: {}", device_path.display(), e);
+                self.file = None;
+            }
+        }
+    }
+
+    /// Write the whole LED state again, for a keyboard that lost it. Key
+    /// colors are left to an external lighting tool if `keys` is false.
+    fn replay(&mut self, keys: bool) {
+        if let Some(profile) = self.profile_led {
+            self.write(&events::led_command(profile));
+        }
+        self.set_mr_led(self.mr_led);
+        if keys {
+            self.keys.invalidate();
+            self.flush();
+        } else {
+            self.keys.direct_mode = false;
+        }
+    }
+
+    fn set_profile_led(&mut self, profile: u8) {
+        self.profile_led = Some(profile);
+        self.write(&events::led_command(profile));
+    }
 
+    fn set_mr_led(&mut self, on: bool) {
+        self.mr_led = on;
+        // Set flag before writing MR LED command
+        self.mr_led_write_time
+            .store(current_time_ms(), Ordering::SeqCst);
+        self.write(&events::mr_led_command(on));
+    }
+
+    /// Set key colors, sending the ones that changed
+    fn set_keys(&mut self, colors: &[(u8, (u8, u8, u8))]) {
+        self.keys.set(colors);
+        self.flush();
+    }
+
+    /// Send the desired key colors that changed, through OpenRGB for the
+    /// G-keys in its `send` mode
+    fn flush(&mut self) {
+        if self.file.is_none() {
+            return;
+        }
+        let mut changed = self.keys.changes(self.level);
+        if let Some(link) = self.openrgb.as_mut().filter(|link| link.sends()) {
+            let gkeys: Vec<_> = changed
+                .iter()
+                .filter_map(|&(address, color)| Some((keymap::gkey_at(address)?, color)))
+                .collect();
+            // Falls back to writing them directly while OpenRGB is unavailable
+            if !gkeys.is_empty() && link.set_gkeys(&gkeys) {
+                changed.retain(|&(address, _)| keymap::gkey_at(address).is_none());
+            }
+        }
+        for report in self.keys.reports(&changed) {
+            self.write(&report);
+        }
+    }
+
+    /// Save the current OpenRGB colors of `gkeys` before covering them with
+    /// temporary feedback, unless they're saved already
+    fn save_gkeys(&mut self, saved: &mut [Option<(u8, u8, u8)>; 5], gkeys: &[u8]) {
+        let Some(link) = self.openrgb.as_mut() else {
+            return;
+        };
+        let unsaved: Vec<usize> = gkeys
+            .iter()
+            .map(|&gkey| usize::from(gkey).wrapping_sub(1))
+            .filter(|&i| saved.get(i).is_some_and(Option::is_none))
+            .collect();
+        if unsaved.is_empty() {
+            return;
+        }
+        if let Some(current) = link.gkey_colors() {
+            for i in unsaved {
+                saved[i] = Some(current[i]);
+            }
+        }
+    }
+}
+
+/// The color to put back on a G-key after temporary feedback: `color` if
+/// given, else the color saved from OpenRGB, else off
+fn restore_color(
+    saved: &mut [Option<(u8, u8, u8)>; 5],
+    gkey: u8,
+    color: Option<(u8, u8, u8)>,
+) -> (u8, u8, u8) {
+    let saved = saved
+        .get_mut(usize::from(gkey).wrapping_sub(1))
+        .and_then(Option::take);
+    color.or(saved).unwrap_or((0, 0, 0))
+}
+
+/// LED worker thread function
+fn led_worker(rx: Receiver<LedCommand>, mr_led_write_time: Arc<AtomicU64>) {
     let mut flashing = false;
     let mut flash_on = false;
     let mut last_flash = Instant::now();
+    let mut feedback = GKeyFeedback::default();
+    let mut last_frame = Instant::now();
+    let mut effect: Option<RunningEffect> = None;
+    let mut out = Output::new(mr_led_write_time);
+    let mut suspended = suspended_time();
+    let mut dimmer = Dimmer {
+        config: None,
+        last_activity: Instant::now(),
+    };
+    // G-key colors from OpenRGB, saved while feedback covers them
+    let mut saved = [None; 5];
+    // When to restore the LEDs of a keyboard waking from standby
+    let mut wake_replay: Option<Instant> = None;
 
     loop {
         // Use timeout to handle flashing
-        let timeout = if flashing {
+        let mut timeout = if flashing {
             MR_FLASH_INTERVAL.saturating_sub(last_flash.elapsed())
         } else {
             Duration::from_secs(3600) // Long timeout when not flashing
         };
+        if feedback.is_active() {
+            timeout = timeout.min(FEEDBACK_FRAME_INTERVAL.saturating_sub(last_frame.elapsed()));
+        }
+        if let Some(ref running) = effect {
+            timeout = timeout.min(running.timeout());
+        }
+        timeout = timeout.min(RESUME_CHECK_INTERVAL);
+        if let Some(at) = wake_replay {
+            timeout = timeout.min(at.saturating_duration_since(Instant::now()));
+        }
+        if let Some(deadline) = dimmer.idle_deadline() {
+            let until = deadline.saturating_duration_since(Instant::now());
+            if !until.is_zero() {
+                timeout = timeout.min(until);
+            }
+        }
 
         match rx.recv_timeout(timeout) {
             Ok(cmd) => {
                 log::trace!("LED command: {:?}", cmd);
                 match cmd {
+                    LedCommand::Connect(device_path) => {
+                        out.connect(&device_path, !effect_paused(&effect));
+                    }
+
+                    LedCommand::Disconnect => {
+                        out.file = None;
+                    }
+
                     LedCommand::SetMrLed(on) => {
-                        // Set flag before writing MR LED command
-                        mr_led_write_time.store(current_time_ms(), Ordering::SeqCst);
-                        write_report(&mut file, &events::mr_led_command(on));
+                        out.set_mr_led(on);
                     }
 
                     LedCommand::SetProfileLed(profile) => {
-                        write_report(&mut file, &events::led_command(profile));
+                        out.set_profile_led(profile);
                     }
 
                     LedCommand::SetAllGKeysLed { r, g, b } => {
-                        write_report(&mut file, &events::all_gkeys_led_command(r, g, b));
-                        write_report(&mut file, &events::led_commit_command());
+                        out.save_gkeys(&mut saved, &ALL_GKEYS);
+                        let colors = [(r, g, b); 5];
+                        out.set_keys(&gkey_colors(colors));
                     }
 
                     LedCommand::SetGKeysRecording { selected_gkey } => {
-                        for g in 1..=5u8 {
-                            let (r, gv, b) = if g == selected_gkey {
+                        out.save_gkeys(&mut saved, &ALL_GKEYS);
+                        let colors = std::array::from_fn(|i| {
+                            if usize::from(selected_gkey) == i + 1 {
                                 (255, 0, 0) // Red for selected
                             } else {
                                 (0, 0, 0) // Off for others
-                            };
-                            write_report(&mut file, &events::gkey_led_command(g, r, gv, b));
-                        }
-                        write_report(&mut file, &events::led_commit_command());
+                            }
+                        });
+                        out.set_keys(&gkey_colors(colors));
                     }
 
                     LedCommand::StartMrFlashing => {
                         flashing = true;
                         flash_on = true;
                         last_flash = Instant::now();
-                        // Set flag before writing MR LED command
-                        mr_led_write_time.store(current_time_ms(), Ordering::SeqCst);
-                        write_report(&mut file, &events::mr_led_command(true));
+                        out.set_mr_led(true);
                     }
 
                     LedCommand::StopMrFlashing => {
                         flashing = false;
-                        // Set flag before writing MR LED command
-                        mr_led_write_time.store(current_time_ms(), Ordering::SeqCst);
-                        write_report(&mut file, &events::mr_led_command(false));
+                        out.set_mr_led(false);
                     }
 
                     LedCommand::QuickFlashMr { count } => {
                         flashing = false;
                         for _ in 0..count {
-                            // Set flag before each MR LED command
-                            mr_led_write_time.store(current_time_ms(), Ordering::SeqCst);
-                            write_report(&mut file, &events::mr_led_command(true));
+                            out.set_mr_led(true);
                             thread::sleep(MR_QUICK_FLASH_INTERVAL);
-                            mr_led_write_time.store(current_time_ms(), Ordering::SeqCst);
-                            write_report(&mut file, &events::mr_led_command(false));
+                            out.set_mr_led(false);
                             thread::sleep(MR_QUICK_FLASH_INTERVAL);
                         }
                     }
 
                     LedCommand::SetFullKeyboardColor { r, g, b } => {
-                        // Send initialization sequence first (required for direct mode)
-                        for cmd in events::direct_mode_init_commands() {
-                            write_report(&mut file, &cmd);
-                        }
-                        // Set all keys to the specified color
-                        for cmd in events::full_keyboard_color_commands(r, g, b) {
-                            write_report(&mut file, &cmd);
-                        }
-                        write_report(&mut file, &events::led_commit_command());
+                        // An explicit full color is always sent in full
+                        out.keys.invalidate();
+                        let colors: Vec<_> = keymap::KEYS
+                            .iter()
+                            .map(|key| (key.address, (r, g, b)))
+                            .collect();
+                        out.set_keys(&colors);
+                    }
+
+                    LedCommand::Reapply => {
+                        out.keys.invalidate();
                     }
 
                     LedCommand::RestoreGKeysColor { color } => {
-                        match color {
-                            Some((r, g, b)) => {
-                                write_report(&mut file, &events::all_gkeys_led_command(r, g, b));
-                            }
-                            None => {
-                                write_report(&mut file, &events::all_gkeys_led_command(0, 0, 0));
+                        let colors =
+                            std::array::from_fn(|i| restore_color(&mut saved, i as u8 + 1, color));
+                        out.set_keys(&gkey_colors(colors));
+                    }
+
+                    LedCommand::SetKeyColors { colors } => {
+                        out.set_keys(&colors);
+                    }
+
+                    LedCommand::SetGKeyColors { colors } => {
+                        out.set_keys(&gkey_colors(colors));
+                    }
+
+                    LedCommand::MacroStarted { gkey } => {
+                        // OpenRGB shows feedback still on the key, so keep
+                        // the color it was going to restore instead
+                        match feedback.get(gkey) {
+                            Some(Feedback::Flashing { restore, .. }) => {
+                                let index = usize::from(gkey).wrapping_sub(1);
+                                if let Some(slot) = saved.get_mut(index) {
+                                    *slot = Some(restore);
+                                }
                             }
+                            Some(Feedback::Pulsing { .. }) => {}
+                            None => out.save_gkeys(&mut saved, &[gkey]),
+                        }
+                        feedback.start(gkey, Instant::now());
+                    }
+
+                    LedCommand::MacroFinished {
+                        gkey,
+                        flash,
+                        restore,
+                    } => {
+                        let restore = restore_color(&mut saved, gkey, restore);
+                        feedback.finish(gkey, flash, restore, Instant::now());
+                    }
+
+                    LedCommand::SetOpenRgb(config) => {
+                        let current = out.openrgb.as_ref().map(openrgb::Link::config);
+                        if current != config.as_ref() {
+                            out.openrgb = config.map(openrgb::Link::new);
+                            saved = [None; 5];
                         }
-                        write_report(&mut file, &events::led_commit_command());
+                    }
+
+                    LedCommand::StartEffect {
+                        effect: new_effect,
+                        fps,
+                        base,
+                        yield_to,
+                    } => {
+                        let now = Instant::now();
+                        log::debug!("Starting lighting effect {:?} at {} fps", new_effect, fps);
+                        let mut running = RunningEffect {
+                            renderer: EffectRenderer::new(new_effect, &base, now),
+                            frame_interval: Duration::from_secs(1) / fps.max(1),
+                            last_frame: now,
+                            yield_to,
+                            paused_for: None,
+                            last_check: now,
+                        };
+                        running.check_external_tool();
+                        effect = Some(running);
+                    }
+
+                    LedCommand::StopEffect => {
+                        if effect.take().is_some() {
+                            log::debug!("Stopped lighting effect");
+                        }
+                    }
+
+                    LedCommand::ResetEffectKeys { r, g, b } => {
+                        // While paused the keys belong to the external tool
+                        if effect.is_some() && !effect_paused(&effect) {
+                            let colors: Vec<_> = effects::effect_keys()
+                                .map(|key| (key.address, (r, g, b)))
+                                .collect();
+                            out.set_keys(&colors);
+                        }
+                    }
+
+                    LedCommand::KeyPressed { code } => {
+                        if dimmer.key_pressed(Instant::now()) {
+                            wake_replay = Some(Instant::now() + WAKE_SETTLE);
+                        }
+                        if let Some(ref mut running) = effect {
+                            running.renderer.key_pressed(code, Instant::now());
+                        }
+                    }
+
+                    LedCommand::Activity => {
+                        if dimmer.key_pressed(Instant::now()) {
+                            wake_replay = Some(Instant::now() + WAKE_SETTLE);
+                        }
+                    }
+
+                    LedCommand::SetBrightness(config) => {
+                        dimmer.config = config;
+                    }
+
+                    LedCommand::SetIndicator { address, color } => {
+                        out.keys.set_indicator(address, color);
+                        out.flush();
+                    }
+
+                    LedCommand::ClearIndicators => {
+                        let addresses: Vec<u8> = out.keys.indicators.keys().copied().collect();
+                        for address in addresses {
+                            out.keys.set_indicator(address, None);
+                        }
+                        out.flush();
                     }
 
                     LedCommand::Shutdown => {
                         // Turn off LEDs before exiting
-                        mr_led_write_time.store(current_time_ms(), Ordering::SeqCst);
-                        write_report(&mut file, &events::mr_led_command(false));
-                        write_report(&mut file, &events::all_gkeys_led_command(0, 0, 0));
-                        write_report(&mut file, &events::led_commit_command());
+                        out.set_mr_led(false);
+                        out.set_keys(&gkey_colors([(0, 0, 0); 5]));
                         log::debug!("LED worker shutting down");
                         break;
                     }
@@ -300,9 +1082,7 @@ fn led_worker(
                 if flashing && last_flash.elapsed() >= MR_FLASH_INTERVAL {
                     flash_on = !flash_on;
                     last_flash = Instant::now();
-                    // Set flag before writing MR LED command
-                    mr_led_write_time.store(current_time_ms(), Ordering::SeqCst);
-                    write_report(&mut file, &events::mr_led_command(flash_on));
+                    out.set_mr_led(flash_on);
                 }
             }
 
@@ -311,14 +1091,259 @@ fn led_worker(
                 break;
             }
         }
+
+        // Macro feedback frames, also when commands keep arriving
+        if feedback.is_active() && last_frame.elapsed() >= FEEDBACK_FRAME_INTERVAL {
+            last_frame = Instant::now();
+            let changed: Vec<_> = feedback
+                .frame(last_frame)
+                .into_iter()
+                .map(|(gkey, color)| (keymap::gkey_address(gkey), color))
+                .collect();
+            out.set_keys(&changed);
+        }
+
+        if let Some(ref mut running) = effect {
+            if running.last_check.elapsed() >= EXTERNAL_TOOL_CHECK_INTERVAL
+                && running.check_external_tool()
+            {
+                // The tool may have changed the mode and any key
+                out.keys.invalidate();
+            }
+            if running.paused_for.is_none()
+                && running.last_frame.elapsed() >= running.frame_interval
+            {
+                running.last_frame = Instant::now();
+                let frame = running.renderer.frame(running.last_frame);
+                out.set_keys(&frame);
+            }
+        }
+
+        let level = dimmer.level(local_time(), Instant::now());
+        if level != out.level {
+            log::info!("Lighting brightness {}%", level);
+            out.level = level;
+            if !effect_paused(&effect) {
+                out.flush();
+            }
+        }
+
+        // The keyboard may have lost its LED state while the system slept
+        let now_suspended = suspended_time();
+        if now_suspended > suspended + RESUME_THRESHOLD {
+            log::info!("System resumed, restoring LEDs");
+            out.replay(!effect_paused(&effect));
+        }
+        suspended = now_suspended;
+
+        // The keyboard's own standby ends with the first key press after a
+        // while, and leaves it on its built-in lighting
+        if wake_replay.is_some_and(|at| Instant::now() >= at) {
+            wake_replay = None;
+            log::debug!("Key pressed after standby, restoring LEDs");
+            out.replay(!effect_paused(&effect));
+        }
     }
+}
+
+/// Whether an effect is paused for an external lighting tool
+fn effect_paused(effect: &Option<RunningEffect>) -> bool {
+    effect
+        .as_ref()
+        .is_some_and(|running| running.paused_for.is_some())
+}
 
-    Ok(())
+/// Total time the system has spent suspended since boot
+fn suspended_time() -> Duration {
+    let read = |clock| {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // SAFETY: clock_gettime only writes to the timespec passed in, and
+        // can't fail for these clocks
+        unsafe { libc::clock_gettime(clock, &mut ts) };
+        Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+    };
+    // BOOTTIME keeps counting during suspend, MONOTONIC doesn't
+    read(libc::CLOCK_BOOTTIME).saturating_sub(read(libc::CLOCK_MONOTONIC))
 }
 
-/// Write a HID report to the device, logging errors
-fn write_report(file: &mut std::fs::File, data: &[u8; 20]) {
-    if let Err(e) = file.write_all(data) {
-        log::warn!("Failed to write LED report: {}", e);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gkey_feedback() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut feedback = GKeyFeedback::default();
+        feedback.start(2, start);
+        assert!(feedback.is_active());
+
+        // Pulse starts dim and peaks half way through the period
+        let dim = feedback.frame(at(0));
+        assert_eq!(dim, vec![(2, (25, 25, 25))]);
+        let half = PULSE_PERIOD.as_millis() as u64 / 2;
+        assert_eq!(feedback.frame(at(half)), vec![(2, (255, 255, 255))]);
+
+        // Unchanged colors are not resent
+        feedback.finish(2, Some((0, 255, 0)), (1, 2, 3), at(2000));
+        assert!(matches!(
+            feedback.get(2),
+            Some(Feedback::Flashing {
+                restore: (1, 2, 3),
+                ..
+            })
+        ));
+        assert!(feedback.get(1).is_none());
+        assert_eq!(feedback.frame(at(2000)), vec![(2, (0, 255, 0))]);
+        assert!(feedback.frame(at(2010)).is_empty());
+        assert_eq!(feedback.frame(at(2160)), vec![(2, (0, 0, 0))]);
+        assert_eq!(feedback.frame(at(3000)), vec![(2, (1, 2, 3))]);
+        assert!(!feedback.is_active());
+
+        // Cancelled macros go straight back to the restore color
+        feedback.start(5, at(0));
+        feedback.finish(5, None, (9, 9, 9), at(100));
+        assert_eq!(feedback.frame(at(100)), vec![(5, (9, 9, 9))]);
+        assert!(!feedback.is_active());
+
+        // Out of range G-keys are ignored
+        feedback.start(6, at(0));
+        assert!(!feedback.is_active());
+    }
+
+    #[test]
+    fn test_key_state() {
+        let mut keys = KeyState::default();
+        let update = |keys: &mut KeyState, colors: &[(u8, (u8, u8, u8))]| {
+            keys.set(colors);
+            let changed = keys.changes(100);
+            keys.reports(&changed)
+        };
+        let esc = keymap::by_name("esc").unwrap().address;
+        let g1 = keymap::gkey_address(1);
+
+        // G-keys alone don't need direct mode
+        let reports = update(&mut keys, &gkey_colors([(255, 0, 0); 5]));
+        assert_eq!(reports.len(), 2);
+        assert_eq!(
+            reports[0][3..13],
+            [0x6c, 255, 0, 0, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xff]
+        );
+        assert_eq!(reports[1], events::led_commit_command());
+        assert!(update(&mut keys, &gkey_colors([(255, 0, 0); 5])).is_empty());
+
+        // Only changed keys are sent, after setting up direct mode once
+        let reports = update(&mut keys, &[(g1, (255, 0, 0)), (esc, (0, 0, 255))]);
+        assert_eq!(reports.len(), 6);
+        assert_eq!(reports[..4], events::direct_mode_init_commands());
+        assert_eq!(reports[4][4..9], [0, 0, 255, esc, 0xff]);
+        let reports = update(&mut keys, &[(esc, (0, 255, 0))]);
+        assert_eq!(reports.len(), 2);
+
+        // The whole keyboard in one color takes 9 reports plus the commit
+        keys.invalidate();
+        let all: Vec<_> = keymap::KEYS
+            .iter()
+            .map(|key| (key.address, (1, 2, 3)))
+            .collect();
+        assert_eq!(update(&mut keys, &all).len(), 4 + 9 + 1);
+    }
+
+    #[test]
+    fn test_replay() {
+        let path = std::env::temp_dir().join(format!("gkeys-rs-led-{}", std::process::id()));
+        let written = || std::fs::read(&path).unwrap().len() / 20;
+        let mut out = Output::new(Arc::new(AtomicU64::new(0)));
+
+        // State set while disconnected is kept and written on connect
+        out.set_profile_led(2);
+        out.set_keys(&gkey_colors([(255, 0, 0); 5]));
+        File::create(&path).unwrap();
+        out.connect(&path, true);
+        // Profile, MR, G-keys and commit
+        assert_eq!(written(), 4);
+        out.set_keys(&gkey_colors([(255, 0, 0); 5]));
+        assert_eq!(written(), 4);
+
+        // A new connection gets all of it again
+        out.file = None;
+        File::create(&path).unwrap();
+        out.connect(&path, true);
+        assert_eq!(written(), 4);
+
+        // Unless an external tool owns the keys
+        File::create(&path).unwrap();
+        out.connect(&path, false);
+        assert_eq!(written(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_indicators() {
+        let mut keys = KeyState::default();
+        let g1 = keymap::gkey_address(1);
+        let logo = keymap::by_name("logo").unwrap().address;
+        keys.set(&[(g1, (0, 0, 255))]);
+        keys.changes(100);
+
+        // Indicators cover the desired color, which shows again afterwards
+        keys.set_indicator(g1, Some((255, 0, 0)));
+        keys.set_indicator(logo, Some((0, 255, 0)));
+        assert_eq!(
+            keys.changes(100),
+            vec![(g1, (255, 0, 0)), (logo, (0, 255, 0))]
+        );
+        keys.set(&[(g1, (0, 255, 255))]);
+        assert!(keys.changes(100).is_empty());
+        keys.set_indicator(g1, None);
+        keys.set_indicator(logo, None);
+        assert_eq!(
+            keys.changes(100),
+            vec![(g1, (0, 255, 255)), (logo, (0, 0, 0))]
+        );
+    }
+
+    #[test]
+    fn test_brightness() {
+        let mut keys = KeyState::default();
+        let g1 = keymap::gkey_address(1);
+        keys.set(&[(g1, (200, 100, 0))]);
+        assert_eq!(keys.changes(50), vec![(g1, (100, 50, 0))]);
+        assert!(keys.changes(50).is_empty());
+        assert_eq!(keys.changes(0), vec![(g1, (0, 0, 0))]);
+
+        let start = Instant::now();
+        let noon = TimeOfDay(12 * 60);
+        let mut dimmer = Dimmer {
+            config: None,
+            last_activity: start,
+        };
+        assert_eq!(dimmer.level(noon, start + Duration::from_secs(3600)), 100);
+        dimmer.config = Some(Brightness {
+            level: 40,
+            schedule: Vec::new(),
+            idle_secs: Some(60),
+        });
+        assert_eq!(dimmer.level(noon, start + Duration::from_secs(59)), 40);
+        assert_eq!(dimmer.level(noon, start + Duration::from_secs(60)), 0);
+        // The press that ends the idle time may also wake the keyboard
+        assert!(dimmer.key_pressed(start + Duration::from_secs(61)));
+        assert_eq!(dimmer.level(noon, start + Duration::from_secs(62)), 40);
+        assert!(!dimmer.key_pressed(start + Duration::from_secs(62)));
+    }
+
+    #[test]
+    fn test_restore_color() {
+        let mut saved = [None, Some((1, 2, 3)), None, None, None];
+        assert_eq!(restore_color(&mut saved, 1, None), (0, 0, 0));
+        assert_eq!(restore_color(&mut saved, 2, None), (1, 2, 3));
+        // Taken once restored
+        assert_eq!(restore_color(&mut saved, 2, None), (0, 0, 0));
+        saved[2] = Some((1, 2, 3));
+        assert_eq!(restore_color(&mut saved, 3, Some((9, 9, 9))), (9, 9, 9));
+        assert_eq!(saved, [None; 5]);
     }
 }
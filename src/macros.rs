@@ -1,54 +1,609 @@
 //! Macro execution engine
+//!
+//! Macros run on a dedicated executor thread so long typeouts and sequences
+//! don't block reading G-key events from hidraw.
 
-use std::process::{Command, Stdio};
+use std::collections::VecDeque;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 
-use crate::config::{HotkeyType, Macro};
-use crate::uinput::VirtualKeyboard;
+use crate::command;
+use crate::config::{Config, HotkeyType, Macro, OutputAction, RepeatPolicy, Typing};
+use crate::layout::Layout;
+use crate::uinput::{Cancelled, Timing, VirtualKeyboard};
+
+/// Identifies a macro slot: a G-key within a profile
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacroId {
+    pub profile: String,
+    pub gkey: u8,
+}
+
+impl fmt::Display for MacroId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "G{} ({})", self.gkey, self.profile)
+    }
+}
+
+/// How a macro run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacroOutcome {
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+/// Progress of macros with `led_feedback`, reported to the main loop
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MacroEvent {
+    Started(MacroId),
+    Finished(MacroId, MacroOutcome),
+}
+
+/// Sends the finish event of a `led_feedback` macro
+#[derive(Clone)]
+struct FeedbackReporter {
+    id: MacroId,
+    events: Sender<MacroEvent>,
+}
+
+impl FeedbackReporter {
+    fn finish(self, outcome: MacroOutcome) {
+        // The main loop may be gone during shutdown; nothing to show then
+        let _ = self.events.send(MacroEvent::Finished(self.id, outcome));
+    }
+}
+
+/// A queued macro execution
+struct Job {
+    id: MacroId,
+    macro_def: Macro,
+    cancel: Arc<AtomicBool>,
+}
+
+/// Work shared between the main loop and the executor thread
+#[derive(Default)]
+struct Queue {
+    pending: VecDeque<Job>,
+    /// Macro currently executing and its cancel flag
+    running: Option<(MacroId, Arc<AtomicBool>)>,
+    /// Reloaded config to apply before the next macro
+    config: Option<Box<Config>>,
+    shutdown: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Cancel the running macro and drop queued ones.
+    /// Returns true if anything was running or queued.
+    fn cancel_all(&self) -> bool {
+        let mut queue = self.lock();
+        let dropped = queue.pending.len();
+        queue.pending.clear();
+        let running = match &queue.running {
+            Some((id, cancel)) => {
+                log::info!("Cancelling {}", id);
+                cancel.store(true, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        };
+        if dropped > 0 {
+            log::info!("Dropped {} queued macros", dropped);
+        }
+        running || dropped > 0
+    }
+}
+
+/// Handle for cancelling macros from other threads (e.g. the control socket)
+#[derive(Clone)]
+pub struct MacroCanceller(Arc<Shared>);
+
+impl MacroCanceller {
+    /// Cancel the running macro and drop queued ones
+    pub fn cancel_all(&self) -> bool {
+        self.0.cancel_all()
+    }
+}
+
+/// Runs macros on a dedicated thread, in the order their G-keys were pressed
+pub struct MacroWorker {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+    events: Receiver<MacroEvent>,
+}
+
+impl MacroWorker {
+    /// Start the executor thread
+    pub fn new(executor: MacroExecutor) -> Result<Self> {
+        let shared = Arc::new(Shared::default());
+        let shared_clone = shared.clone();
+        let (tx, events) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("macro-executor".into())
+            .spawn(move || worker_loop(executor, shared_clone, tx))
+            .context("Failed to spawn macro executor thread")?;
+
+        Ok(Self {
+            shared,
+            thread: Some(thread),
+            events,
+        })
+    }
+
+    /// Queue a macro, applying its repeat policy if the same G-key is
+    /// already running or queued
+    pub fn submit(&self, id: MacroId, macro_def: Macro) {
+        let mut queue = self.shared.lock();
+        let running = queue
+            .running
+            .as_ref()
+            .filter(|(running_id, _)| *running_id == id)
+            .map(|(_, cancel)| cancel.clone());
+        let queued = queue.pending.iter().any(|job| job.id == id);
+
+        let job = Job {
+            id,
+            macro_def,
+            cancel: Arc::new(AtomicBool::new(false)),
+        };
+
+        if running.is_none() && !queued {
+            queue.pending.push_back(job);
+            self.shared.ready.notify_one();
+            return;
+        }
+
+        match job.macro_def.on_repeat {
+            RepeatPolicy::Queue => {
+                log::debug!("{} already running, queueing", job.id);
+                queue.pending.push_back(job);
+            }
+            RepeatPolicy::Ignore => {
+                log::debug!("{} already running, ignoring press", job.id);
+                return;
+            }
+            RepeatPolicy::Restart => {
+                log::info!("Restarting {}", job.id);
+                if let Some(cancel) = running {
+                    cancel.store(true, Ordering::SeqCst);
+                }
+                queue.pending.retain(|queued| queued.id != job.id);
+                queue.pending.push_front(job);
+            }
+            RepeatPolicy::Cancel => {
+                log::info!("Cancelling {}", job.id);
+                if let Some(cancel) = running {
+                    cancel.store(true, Ordering::SeqCst);
+                }
+                queue.pending.retain(|queued| queued.id != job.id);
+                return;
+            }
+        }
+        self.shared.ready.notify_one();
+    }
+
+    /// Cancel the running macro and drop queued ones.
+    /// Returns true if anything was running or queued.
+    pub fn cancel_all(&self) -> bool {
+        self.shared.cancel_all()
+    }
+
+    /// Apply a reloaded config's global settings before the next macro runs
+    pub fn reconfigure(&self, config: Config) {
+        self.shared.lock().config = Some(Box::new(config));
+        self.shared.ready.notify_one();
+    }
+
+    /// Start and finish events of `led_feedback` macros since the last call
+    pub fn poll_events(&self) -> impl Iterator<Item = MacroEvent> + '_ {
+        self.events.try_iter()
+    }
+
+    /// Get a handle that can cancel macros from another thread
+    pub fn canceller(&self) -> MacroCanceller {
+        MacroCanceller(self.shared.clone())
+    }
+}
+
+impl Drop for MacroWorker {
+    fn drop(&mut self) {
+        {
+            let mut queue = self.shared.lock();
+            queue.shutdown = true;
+            queue.pending.clear();
+            if let Some((_, cancel)) = &queue.running {
+                cancel.store(true, Ordering::SeqCst);
+            }
+        }
+        self.shared.ready.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Executor thread: run queued macros one at a time until shutdown
+fn worker_loop(mut executor: MacroExecutor, shared: Arc<Shared>, events: Sender<MacroEvent>) {
+    log::debug!("Macro executor thread started");
+    loop {
+        let job = {
+            let mut queue = shared.lock();
+            loop {
+                if queue.shutdown {
+                    log::debug!("Macro executor thread shutting down");
+                    return;
+                }
+                if let Some(config) = queue.config.take() {
+                    executor.apply_config(&config);
+                }
+                if let Some(job) = queue.pending.pop_front() {
+                    queue.running = Some((job.id.clone(), job.cancel.clone()));
+                    break job;
+                }
+                queue = shared
+                    .ready
+                    .wait(queue)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+        };
+
+        let feedback = job.macro_def.led_feedback;
+        if feedback {
+            // The main loop may be gone during shutdown; nothing to show then
+            let _ = events.send(MacroEvent::Started(job.id.clone()));
+        }
+
+        executor.feedback = feedback.then(|| FeedbackReporter {
+            id: job.id.clone(),
+            events: events.clone(),
+        });
+        executor.keyboard.set_cancel_flag(job.cancel.clone());
+        // Catch panics so a bug in one macro can't leave keys held or kill the thread
+        let result = panic::catch_unwind(AssertUnwindSafe(|| executor.execute(&job.macro_def)));
+        let outcome = match result {
+            Ok(Ok(())) => {
+                log::debug!("{} finished", job.id);
+                MacroOutcome::Succeeded
+            }
+            Ok(Err(e)) if e.is::<Cancelled>() => {
+                log::info!("{} cancelled", job.id);
+                MacroOutcome::Cancelled
+            }
+            Ok(Err(e)) => {
+                log::error!("Failed to execute macro {}: {:#}", job.id, e);
+                MacroOutcome::Failed
+            }
+            Err(_) => {
+                log::error!("Macro {} panicked", job.id);
+                MacroOutcome::Failed
+            }
+        };
+
+        // Don't leave modifiers stuck down after a cancel, error or panic
+        if executor.keyboard.has_held_keys() {
+            log::info!("Releasing keys still held after {}", job.id);
+            if let Err(e) = executor.keyboard.release_all() {
+                log::error!("Failed to release held keys: {:#}", e);
+            }
+        }
+
+        shared.lock().running = None;
+        // Unless a background command took over reporting its result
+        if let Some(reporter) = executor.feedback.take() {
+            reporter.finish(outcome);
+        }
+    }
+}
+
+/// Timeout for `typecmd` and secret commands without their own `timeout_ms`
+const TYPECMD_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct MacroExecutor {
     keyboard: VirtualKeyboard,
+    /// Global typing speed, merged with each macro's overrides
+    typing: Typing,
+    /// Log actions instead of performing them
+    dry_run: bool,
+    /// Set while a secret macro is typing, so recordings skip its keys
+    secret_active: Arc<AtomicBool>,
+    /// Finish event of the running macro, if it has `led_feedback`
+    feedback: Option<FeedbackReporter>,
+}
+
+/// Clears the secret flag when a secret macro ends, even by panic
+struct SecretGuard(Arc<AtomicBool>);
+
+impl Drop for SecretGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
 }
 
 impl MacroExecutor {
-    pub fn new() -> Result<Self> {
-        let keyboard = VirtualKeyboard::new()?;
-        Ok(Self { keyboard })
+    pub fn new(config: &Config, dry_run: bool) -> Result<Self> {
+        let keyboard = if dry_run {
+            VirtualKeyboard::dry_run()
+        } else {
+            VirtualKeyboard::new()?
+        };
+        let mut executor = Self {
+            keyboard,
+            typing: Typing::default(),
+            dry_run,
+            secret_active: Arc::new(AtomicBool::new(false)),
+            feedback: None,
+        };
+        executor.apply_config(config);
+        Ok(executor)
+    }
+
+    /// Flag that is set while a secret macro runs
+    pub fn secret_flag(&self) -> Arc<AtomicBool> {
+        self.secret_active.clone()
+    }
+
+    /// Apply global settings (layout, clipboard, typing speed) from the config
+    pub fn apply_config(&mut self, config: &Config) {
+        let layout = Layout::from_name(&config.keyboard_mapping).unwrap_or_else(|e| {
+            log::warn!("{:#} - falling back to US layout", e);
+            Layout::us()
+        });
+        self.keyboard.set_layout(layout);
+        self.keyboard.set_clipboard(config.clipboard.clone());
+        self.typing = config.typing.clone();
     }
 
     /// Execute a macro based on its type
     pub fn execute(&mut self, macro_def: &Macro) -> Result<()> {
+        if self.dry_run {
+            if macro_def.is_secret() {
+                log::info!("Dry run: {:?} macro <redacted>", macro_def.hotkey_type);
+            } else {
+                log::info!(
+                    "Dry run: {:?} macro '{}'",
+                    macro_def.hotkey_type,
+                    macro_def.action
+                );
+            }
+        }
+        self.keyboard
+            .set_timing(Timing::from(&macro_def.typing.or(&self.typing)));
+
+        let _secret_guard = macro_def.is_secret().then(|| {
+            self.secret_active.store(true, Ordering::SeqCst);
+            SecretGuard(self.secret_active.clone())
+        });
+
         match macro_def.hotkey_type {
-            HotkeyType::Run => self.run_command(&macro_def.action),
-            HotkeyType::Shortcut => self.keyboard.shortcut(&macro_def.action),
-            HotkeyType::Typeout => self.keyboard.typeout(&macro_def.action),
-            HotkeyType::Uinput => self.emit_uinput_key(&macro_def.action),
-            HotkeyType::Sequence => self.keyboard.sequence(&macro_def.action),
+            HotkeyType::Run => self.run_command(macro_def),
+            HotkeyType::Typeout if macro_def.secret.is_some() => self.type_secret(macro_def),
+            HotkeyType::Typeout => self.keyboard.typeout(&macro_def.action, macro_def.fallback),
+            HotkeyType::Shortcut
+            | HotkeyType::Uinput
+            | HotkeyType::Sequence
+            | HotkeyType::Steps => self
+                .keyboard
+                .run_actions(&macro_def.actions, macro_def.fallback),
+            HotkeyType::Typecmd => self.type_command(macro_def),
             HotkeyType::Nothing => Ok(()),
         }
     }
 
-    /// Run a shell command
-    fn run_command(&self, cmd: &str) -> Result<()> {
-        log::debug!("Running command: {}", cmd);
-        Command::new("/bin/sh")
-            .arg("-c")
-            .arg(cmd)
-            .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .spawn()?;
-        Ok(())
+    /// Run a command, handling its output as configured
+    fn run_command(&mut self, macro_def: &Macro) -> Result<()> {
+        if self.dry_run {
+            log::info!("Dry run: not running command");
+            return Ok(());
+        }
+        let options = &macro_def.run;
+        if options.output == OutputAction::Discard {
+            let cmd = command::build(&macro_def.action, options)?;
+            log::debug!("Running command: {}", command::program_name(&cmd));
+            let timeout = options.timeout_ms.map(Duration::from_millis);
+            // LED feedback shows the exit status once the reaper sees it
+            let reporter = self.feedback.clone();
+            command::spawn_reaped(cmd, timeout, move |success| {
+                if let Some(reporter) = reporter {
+                    reporter.finish(if success {
+                        MacroOutcome::Succeeded
+                    } else {
+                        MacroOutcome::Failed
+                    });
+                }
+            })?;
+            self.feedback = None;
+            return Ok(());
+        }
+
+        let output = self.capture_output(macro_def, None)?;
+        let output = output.trim_end_matches(['\r', '\n']);
+        match options.output {
+            OutputAction::Type => self.keyboard.typeout(output, macro_def.fallback),
+            OutputAction::Notify => {
+                command::notify("gkeys-rs", output);
+                Ok(())
+            }
+            OutputAction::Log => {
+                log::info!("Command output: {}", output);
+                Ok(())
+            }
+            OutputAction::Discard => Ok(()),
+        }
     }
 
-    /// Emit a direct uinput key (e.g., "KEY_F13")
-    fn emit_uinput_key(&mut self, key_name: &str) -> Result<()> {
-        if let Some(key) = VirtualKeyboard::parse_key(key_name) {
-            self.keyboard.click(key)?;
-        } else {
-            log::warn!("Unknown uinput key: {}", key_name);
+    /// Type the trimmed output of a command; the output is never logged
+    fn type_command(&mut self, macro_def: &Macro) -> Result<()> {
+        if self.dry_run {
+            log::info!("Dry run: not running command");
+            return Ok(());
+        }
+        let output = self.capture_output(macro_def, Some(TYPECMD_TIMEOUT))?;
+        self.keyboard
+            .typeout_redacted(output.trim(), macro_def.fallback)
+    }
+
+    /// Read a secret macro's text and type it; the text is never logged
+    fn type_secret(&mut self, macro_def: &Macro) -> Result<()> {
+        let Some(ref source) = macro_def.secret else {
+            return Ok(());
+        };
+        if self.dry_run {
+            log::info!("Dry run: not reading secret");
+            return Ok(());
+        }
+        let timeout = macro_def
+            .run
+            .timeout_ms
+            .map_or(TYPECMD_TIMEOUT, Duration::from_millis);
+        let cancel = self.keyboard.cancel_flag();
+        let secret = source.resolve(&macro_def.run, timeout, || cancel.load(Ordering::SeqCst))?;
+        self.keyboard
+            .typeout_redacted(secret.as_str(), macro_def.fallback)
+    }
+
+    /// Run a macro's command to completion and return its stdout, failing
+    /// on a non-zero exit status
+    fn capture_output(
+        &self,
+        macro_def: &Macro,
+        default_timeout: Option<Duration>,
+    ) -> Result<String> {
+        let options = &macro_def.run;
+        let cmd = command::build(&macro_def.action, options)?;
+        log::debug!("Running command: {}", command::program_name(&cmd));
+        let timeout = options
+            .timeout_ms
+            .map(Duration::from_millis)
+            .or(default_timeout);
+
+        let cancel = self.keyboard.cancel_flag();
+        let (status, output) =
+            command::run_captured(cmd, timeout, || cancel.load(Ordering::SeqCst))?;
+        if !status.success() {
+            bail!("Command exited with {}", command::describe(status));
         }
-        Ok(())
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{OutputEvent, RecordingBackend};
+    use crate::uinput::keys;
+
+    fn recording_executor() -> (MacroExecutor, RecordingBackend) {
+        let backend = RecordingBackend::default();
+        let executor = MacroExecutor {
+            keyboard: VirtualKeyboard::with_backend(Box::new(backend.clone())),
+            typing: Typing::default(),
+            dry_run: false,
+            secret_active: Arc::new(AtomicBool::new(false)),
+            feedback: None,
+        };
+        (executor, backend)
+    }
+
+    #[test]
+    fn test_typecmd() {
+        let (mut executor, backend) = recording_executor();
+        let mut macro_def: Macro =
+            serde_json::from_str(r#"{ "hotkey_type": "typecmd", "argv": ["printf", " 42 \n"] }"#)
+                .unwrap();
+        macro_def.compile().unwrap();
+        executor.execute(&macro_def).unwrap();
+        assert_eq!(
+            backend.events(),
+            vec![
+                OutputEvent::Key(keys::KEY_4, 1),
+                OutputEvent::Key(keys::KEY_4, 0),
+                OutputEvent::Key(keys::KEY_2, 1),
+                OutputEvent::Key(keys::KEY_2, 0),
+            ]
+        );
+
+        // A failing command types nothing
+        let (mut executor, backend) = recording_executor();
+        macro_def.run.argv = vec!["sh".into(), "-c".into(), "echo 1; exit 1".into()];
+        assert!(executor.execute(&macro_def).is_err());
+        assert!(backend.events().is_empty());
+    }
+
+    #[test]
+    fn test_secret_typeout() {
+        let (mut executor, backend) = recording_executor();
+        let mut macro_def: Macro = serde_json::from_str(
+            r#"{ "hotkey_type": "typeout", "secret": { "command": "printf '42\\n'" } }"#,
+        )
+        .unwrap();
+        macro_def.compile().unwrap();
+        executor.execute(&macro_def).unwrap();
+        assert_eq!(
+            backend.events(),
+            vec![
+                OutputEvent::Key(keys::KEY_4, 1),
+                OutputEvent::Key(keys::KEY_4, 0),
+                OutputEvent::Key(keys::KEY_2, 1),
+                OutputEvent::Key(keys::KEY_2, 0),
+            ]
+        );
+        // The recorder may capture keys again once the macro is done
+        assert!(!executor.secret_flag().load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_background_command_feedback() {
+        let (executor, _backend) = recording_executor();
+        let worker = MacroWorker::new(executor).unwrap();
+        let id = |gkey| MacroId {
+            profile: "MEMORY_1".into(),
+            gkey,
+        };
+        let parse = |json: &str| {
+            let mut macro_def: Macro = serde_json::from_str(json).unwrap();
+            macro_def.compile().unwrap();
+            macro_def
+        };
+        let next = || worker.events.recv_timeout(Duration::from_secs(10)).unwrap();
+
+        // G1's command runs until the test lets it exit
+        let gate = std::env::temp_dir().join(format!("gkeys-rs-gate-{}", std::process::id()));
+        let _ = std::fs::remove_file(&gate);
+        let run = serde_json::json!({
+            "hotkey_type": "run",
+            "do": "while [ ! -e \"$GATE\" ]; do sleep 0.01; done; exit 1",
+            "env": { "GATE": gate },
+            "led_feedback": true,
+        });
+        worker.submit(id(1), parse(&run.to_string()));
+        worker.submit(
+            id(2),
+            parse(r#"{ "hotkey_type": "typeout", "do": "x", "led_feedback": true }"#),
+        );
+
+        // G2 doesn't wait for G1's command, whose exit status comes last
+        assert_eq!(next(), MacroEvent::Started(id(1)));
+        assert_eq!(next(), MacroEvent::Started(id(2)));
+        assert_eq!(next(), MacroEvent::Finished(id(2), MacroOutcome::Succeeded));
+        std::fs::write(&gate, "").unwrap();
+        assert_eq!(next(), MacroEvent::Finished(id(1), MacroOutcome::Failed));
+        std::fs::remove_file(&gate).unwrap();
     }
 }
@@ -1,11 +1,25 @@
+mod activity;
+mod cli;
+mod command;
 mod config;
 mod device;
+mod effects;
 mod events;
+mod indicators;
+mod ipc;
+mod keycodes;
+mod keymap;
+mod layout;
 mod led;
 mod macros;
+mod openrgb;
+mod output;
 mod recording;
+mod secret;
+mod steps;
 mod uinput;
 
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
@@ -13,24 +27,96 @@ use std::time::Duration;
 
 use anyhow::Result;
 
-use config::{Config, HotkeyType, Macro};
+use activity::KeyListener;
+use cli::{Cli, Command};
+use config::{Config, HotkeyType, Macro, RgbColor};
 use device::Device;
 use events::Event;
+use indicators::{IndicatorWatcher, PushedValues};
+use ipc::IpcServer;
+use layout::Layout;
 use led::LedController;
-use macros::MacroExecutor;
+use macros::{MacroCanceller, MacroEvent, MacroExecutor, MacroId, MacroOutcome, MacroWorker};
 use recording::{Recorder, RecordingAction};
 
 /// Number of quick flashes on successful recording
 const MR_QUICK_FLASH_COUNT: u8 = 4;
 
+/// G-key flash colors for `led_feedback` macros
+const FEEDBACK_SUCCESS_COLOR: (u8, u8, u8) = (0, 255, 0);
+const FEEDBACK_ERROR_COLOR: (u8, u8, u8) = (255, 0, 0);
+
 fn main() -> Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let cli = match Cli::parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("Error: {}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
 
-    log::info!("gkeys-rs starting");
+    match cli.command {
+        Command::Help => {
+            print!("{}", cli::USAGE);
+            return Ok(());
+        }
+        Command::Version => {
+            println!("gkeys-rs {}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+        Command::Cancel | Command::Reload | Command::Indicator { .. } => {
+            let request = match cli.command {
+                Command::Cancel => "cancel".to_string(),
+                Command::Indicator {
+                    ref name,
+                    ref value,
+                } => match value {
+                    Some(value) => format!("indicator {} {}", name, value),
+                    None => format!("indicator {}", name),
+                },
+                _ => "reload".to_string(),
+            };
+            match ipc::socket_path().and_then(|path| ipc::send_command(&path, &request)) {
+                Ok(reply) => println!("{}", reply),
+                Err(e) => {
+                    eprintln!("Error: {:#}", e);
+                    std::process::exit(1);
+                }
+            }
+            return Ok(());
+        }
+        Command::Run | Command::Check => {}
+    }
+
+    let mut logger =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if let Some(ref level) = cli.log_level {
+        logger.parse_filters(level);
+    }
+    logger.init();
 
     // Load config
-    let config_path = Config::config_path()?;
-    let mut config = match Config::load() {
+    let config_path = match cli.config {
+        Some(ref path) => path.clone(),
+        None => Config::config_path()?,
+    };
+
+    if cli.command == Command::Check {
+        let config = Config::load_from(&config_path)?;
+        Layout::from_name(&config.keyboard_mapping)?;
+        let macro_count: usize = config.profiles.values().map(|p| p.macros.len()).sum();
+        println!(
+            "{}: OK ({} profiles, {} macros)",
+            config_path.display(),
+            config.profiles.len(),
+            macro_count
+        );
+        return Ok(());
+    }
+
+    log::info!("gkeys-rs starting");
+
+    let mut config = match Config::load_from(&config_path) {
         Ok(c) => {
             log::info!("Loaded config from {}", config_path.display());
             c
@@ -43,22 +129,62 @@ fn main() -> Result<()> {
     };
 
     // Create macro executor
-    let mut executor = MacroExecutor::new()?;
-    log::info!("Virtual keyboard created");
+    let executor = MacroExecutor::new(&config, cli.dry_run)?;
+    if cli.dry_run {
+        log::info!("Dry run: macro actions will be logged, not sent to uinput");
+    } else {
+        log::info!("Virtual keyboard created");
+    }
+    let secret_flag = executor.secret_flag();
+    let macros = MacroWorker::new(executor)?;
+
+    // Control socket for `gkeys-rs cancel`/`reload` and scripts
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    let pushed_values = PushedValues::default();
+    let handler = control_handler(
+        macros.canceller(),
+        reload_requested.clone(),
+        pushed_values.clone(),
+    );
+    let _ipc_server = match ipc::socket_path().and_then(|path| IpcServer::start(&path, handler)) {
+        Ok(server) => Some(server),
+        Err(e) => {
+            log::warn!("Control socket unavailable: {:#}", e);
+            None
+        }
+    };
 
     // Create macro recorder
     let mut recorder = Recorder::new();
+    recorder.set_secret_flag(secret_flag);
 
     // Current profile (preserved across reconnections)
-    let mut current_profile = String::from("MEMORY_1");
-
-    // LED controller (created per device connection)
-    let mut led_controller: Option<LedController>;
-
-    // Setup signal handling for clean shutdown
+    let mut current_profile = cli.profile.clone().unwrap_or_else(|| String::from("MEMORY_1"));
+
+    // LED controller, which keeps the LED state across reconnections and
+    // replays it whenever the keyboard is opened
+    let led_controller = LedController::new()?;
+    let led = &led_controller;
+
+    // Set profile LED to match current state
+    let profile_num = current_profile
+        .strip_prefix("MEMORY_")
+        .and_then(|s| s.parse::<u8>().ok())
+        .unwrap_or(1);
+    led.set_profile_led(profile_num);
+
+    apply_lighting(&config, &current_profile, led);
+    let mut indicator_watcher = None;
+    update_indicators(&mut indicator_watcher, &config, &pushed_values, led);
+
+    // Setup signal handling (SIGINT, SIGTERM, SIGHUP) for clean shutdown.
+    // Cancelling right away releases keys held by a running macro even if
+    // the main loop is slow to notice.
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
+    let canceller = macros.canceller();
     ctrlc::set_handler(move || {
+        canceller.cancel_all();
         r.store(false, Ordering::SeqCst);
     })?;
 
@@ -68,7 +194,11 @@ fn main() -> Result<()> {
 
     while running.load(Ordering::SeqCst) {
         // Try to open device
-        let mut device = match Device::open() {
+        let open_result = match cli.device {
+            Some(ref path) => Device::open_path(path.clone()),
+            None => Device::open(),
+        };
+        let mut device = match open_result {
             Ok(d) => {
                 log::info!("Opened device: {}", d.path().display());
                 reconnect_delay = Duration::from_secs(1); // Reset delay on success
@@ -82,31 +212,9 @@ fn main() -> Result<()> {
             }
         };
 
-        // Create LED controller for this device
-        let led_ctrl = match LedController::new(device.path().clone()) {
-            Ok(ctrl) => ctrl,
-            Err(e) => {
-                log::error!("Failed to create LED controller: {}", e);
-                thread::sleep(reconnect_delay);
-                reconnect_delay = (reconnect_delay * 2).min(max_reconnect_delay);
-                continue;
-            }
-        };
-        led_controller = Some(led_ctrl);
-        let led = led_controller.as_ref().unwrap();
-
-        // Set profile LED to match current state
-        let profile_num = current_profile
-            .strip_prefix("MEMORY_")
-            .and_then(|s| s.parse::<u8>().ok())
-            .unwrap_or(1);
-        led.set_profile_led(profile_num);
-
-        // Apply configured RGB color to entire keyboard if set
-        if let Some(ref color) = config.rgb_color {
-            log::info!("Setting keyboard color to RGB({}, {}, {})", color.r, color.g, color.b);
-            led.set_full_keyboard_color(color.r, color.g, color.b);
-        }
+        led.connect(device.path().clone());
+        let mut key_listener = None;
+        update_key_listener(&mut key_listener, &config, led);
 
         log::info!("Ready. Listening for G-key events...");
 
@@ -117,35 +225,51 @@ fn main() -> Result<()> {
                 return Ok(());
             }
 
+            if reload_requested.swap(false, Ordering::SeqCst) {
+                reload_config(&mut config, &config_path, &current_profile, &macros, led);
+                update_key_listener(&mut key_listener, &config, led);
+                update_indicators(&mut indicator_watcher, &config, &pushed_values, led);
+            }
+
+            for event in macros.poll_events() {
+                show_macro_feedback(event, &config, &current_profile, led);
+            }
+
             // Poll for captured keys during recording
             if recorder.is_recording() {
                 recorder.poll_captured_keys();
             }
 
             // Use timeout read so we can poll captured keys during recording
-            let event_result = if recorder.is_recording() {
-                device.read_event() // 100ms timeout
-            } else {
-                device.read_event_blocking() // Blocking read when not recording
-            };
+            // and notice shutdown signals without waiting for a key press
+            let event_result = device.read_event(); // 100ms timeout
 
             match event_result {
                 Ok(Some(event)) => {
+                    led.activity();
+
                     // Check if recorder should handle this event
                     if let Some(action) = handle_event_for_recording(
                         &event,
                         &mut recorder,
                         &current_profile,
+                        &macros,
                         led,
                     ) {
-                        handle_recording_action(action, &mut config, led);
+                        handle_recording_action(
+                            action,
+                            &mut config,
+                            &config_path,
+                            &current_profile,
+                            led,
+                        );
                     } else if !recorder.is_recording() && !recorder.is_awaiting() {
                         // Normal macro execution only when not recording
                         handle_event(
                             &event,
                             &config,
                             &mut current_profile,
-                            &mut executor,
+                            &macros,
                             led,
                         );
                     }
@@ -155,6 +279,7 @@ fn main() -> Result<()> {
                     // Check if it's an interrupted system call (from signal)
                     if running.load(Ordering::SeqCst) {
                         log::warn!("Device disconnected: {} - will attempt reconnection", e);
+                        led.disconnect();
                         break; // Break inner loop to try reconnection
                     }
                 }
@@ -166,11 +291,205 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+/// Re-read the config file, keeping the current config if the new one is invalid
+fn reload_config(
+    config: &mut Config,
+    config_path: &Path,
+    current_profile: &str,
+    macros: &MacroWorker,
+    led: &LedController,
+) {
+    match Config::load_from(config_path) {
+        Ok(new_config) => {
+            log::info!("Reloaded config from {}", config_path.display());
+            macros.reconfigure(new_config.clone());
+            *config = new_config;
+            apply_lighting(config, current_profile, led);
+        }
+        Err(e) => {
+            log::error!("Failed to reload config, keeping the old one: {:#}", e);
+            command::notify("Config reload failed", &format!("{:#}", e));
+        }
+    }
+}
+
+/// Apply all configured lighting: the keyboard color, then the profile's
+fn apply_lighting(config: &Config, profile: &str, led: &LedController) {
+    led.reapply();
+    led.set_openrgb(config.openrgb.clone());
+    if let Some(ref color) = config.rgb_color {
+        log::info!("Setting keyboard color to RGB({}, {}, {})", color.r, color.g, color.b);
+        led.set_full_keyboard_color(color.r, color.g, color.b);
+    }
+    apply_profile_lighting(config, profile, led);
+}
+
+/// Apply a profile's key colors and G-key highlight. Profiles without
+/// lighting leave the keys as they are.
+fn apply_profile_lighting(config: &Config, profile: &str, led: &LedController) {
+    match config.get_lighting(profile) {
+        Some(lighting) => {
+            log::debug!("Applying lighting for {}", profile);
+            led.set_key_colors(lighting.key_colors.clone());
+            match lighting.effect {
+                Some(ref effect) => led.start_effect(
+                    effect.clone(),
+                    lighting.fps,
+                    lighting.key_colors.clone(),
+                    config.external_lighting_tools.clone(),
+                ),
+                None => led.stop_effect(),
+            }
+        }
+        None => led.stop_effect(),
+    }
+    if let Some(colors) = config.gkey_highlight_colors(profile) {
+        led.set_gkey_colors(colors);
+    }
+}
+
+/// Listen for key presses, for idle blanking, reactive effects and
+/// restoring the LEDs after the keyboard's standby, and apply the
+/// brightness settings the listener allows
+fn update_key_listener(listener: &mut Option<KeyListener>, config: &Config, led: &LedController) {
+    if listener.is_none() {
+        match KeyListener::start(led.key_sink()) {
+            Ok(started) => *listener = Some(started),
+            Err(e) if config.wants_key_presses() => {
+                log::error!("Key press listener unavailable: {:#}", e)
+            }
+            Err(e) => log::warn!(
+                "Key press listener unavailable, LEDs are restored after standby at the next G-key or M-key press: {:#}",
+                e
+            ),
+        }
+    }
+    let brightness = config.effective_brightness(listener.is_some());
+    if brightness != config.brightness {
+        log::error!("Idle blanking disabled: key presses can't be read");
+    }
+    led.set_brightness(brightness);
+}
+
+/// Pulse or flash a G-key for a `led_feedback` macro
+fn show_macro_feedback(event: MacroEvent, config: &Config, profile: &str, led: &LedController) {
+    match event {
+        MacroEvent::Started(id) => led.macro_started(id.gkey),
+        MacroEvent::Finished(id, outcome) => {
+            let flash = match outcome {
+                MacroOutcome::Succeeded => Some(FEEDBACK_SUCCESS_COLOR),
+                MacroOutcome::Failed => Some(FEEDBACK_ERROR_COLOR),
+                MacroOutcome::Cancelled => None,
+            };
+            led.macro_finished(id.gkey, flash, gkey_color(config, profile, id.gkey));
+        }
+    }
+}
+
+/// The color a G-key should show in a profile: the highlight, then the
+/// profile's lighting, then `rgb_color`. None leaves it to the LED thread
+/// (OpenRGB's color, else off).
+fn gkey_color(config: &Config, profile: &str, gkey: u8) -> Option<(u8, u8, u8)> {
+    if let Some(colors) = config.gkey_highlight_colors(profile) {
+        return Some(colors[usize::from(gkey - 1)]);
+    }
+    let address = keymap::gkey_address(gkey);
+    config
+        .get_lighting(profile)
+        .and_then(|lighting| lighting.key_colors.iter().find(|&&(a, _)| a == address))
+        .map(|&(_, color)| color)
+        .or_else(|| config.rgb_color.as_ref().map(RgbColor::rgb))
+}
+
+/// Put the G-keys back the way the config wants them after recording
+fn restore_gkey_leds(config: &Config, profile: &str, led: &LedController) {
+    // Restore G-key LEDs to configured color (or off if not set)
+    led.restore_gkeys_color(config.rgb_color.as_ref().map(RgbColor::rgb));
+    if let Some(lighting) = config.get_lighting(profile) {
+        let gkeys = lighting
+            .key_colors
+            .iter()
+            .filter(|&&(address, _)| keymap::gkey_at(address).is_some())
+            .copied()
+            .collect();
+        led.set_key_colors(gkeys);
+    }
+    if let Some(colors) = config.gkey_highlight_colors(profile) {
+        led.set_gkey_colors(colors);
+    }
+}
+
+/// Watch the configured status indicators, restarting when they change
+fn update_indicators(
+    watcher: &mut Option<IndicatorWatcher>,
+    config: &Config,
+    pushed: &PushedValues,
+    led: &LedController,
+) {
+    let running = watcher
+        .as_ref()
+        .map_or(&[][..], IndicatorWatcher::indicators);
+    if running == config.indicators.as_slice() {
+        return;
+    }
+    // Stop the old threads before giving their keys back
+    *watcher = None;
+    led.clear_indicators();
+    if config.indicators.is_empty() {
+        return;
+    }
+    match IndicatorWatcher::start(
+        config.indicators.clone(),
+        pushed.clone(),
+        led.indicator_sink(),
+    ) {
+        Ok(started) => *watcher = Some(started),
+        Err(e) => log::warn!("Status indicators unavailable: {:#}", e),
+    }
+}
+
+/// Build the handler for commands received on the control socket
+fn control_handler(
+    canceller: MacroCanceller,
+    reload: Arc<AtomicBool>,
+    pushed: PushedValues,
+) -> ipc::Handler {
+    Box::new(move |command| match command {
+        "cancel" => Ok(if canceller.cancel_all() {
+            "cancelled".to_string()
+        } else {
+            "nothing running".to_string()
+        }),
+        // The main loop picks this up within one read timeout
+        "reload" => {
+            reload.store(true, Ordering::SeqCst);
+            Ok("reloading".to_string())
+        }
+        // "indicator NAME [VALUE]": the indicator threads pick it up on
+        // their next poll
+        _ if command.starts_with("indicator ") => {
+            let mut values = pushed.lock().unwrap_or_else(|e| e.into_inner());
+            match command.split_whitespace().collect::<Vec<_>>()[..] {
+                [_, name] => {
+                    values.remove(name);
+                    Ok(format!("{} cleared", name))
+                }
+                [_, name, value] => {
+                    values.insert(name.to_string(), value.to_string());
+                    Ok(format!("{} = {}", name, value))
+                }
+                _ => anyhow::bail!("Usage: indicator <NAME> [VALUE]"),
+            }
+        }
+        _ => anyhow::bail!("Unknown command '{}'", command),
+    })
+}
+
 fn handle_event(
     event: &Event,
     config: &Config,
     current_profile: &mut String,
-    executor: &mut MacroExecutor,
+    macros: &MacroWorker,
     led: &LedController,
 ) {
     match event {
@@ -179,9 +498,11 @@ fn handle_event(
             log::debug!("G{} pressed (profile: {})", n, current_profile);
 
             if let Some(macro_def) = config.get_macro(current_profile, &macro_name) {
-                if let Err(e) = executor.execute(macro_def) {
-                    log::error!("Failed to execute macro: {}", e);
-                }
+                let id = MacroId {
+                    profile: current_profile.clone(),
+                    gkey: *n,
+                };
+                macros.submit(id, macro_def.clone());
             } else {
                 log::debug!("No macro defined for {} in {}", macro_name, current_profile);
             }
@@ -198,14 +519,15 @@ fn handle_event(
                 *current_profile = new_profile.clone();
 
                 led.set_profile_led(*n);
+                if let Some(ref color) = config.rgb_color {
+                    // Don't leave the keys on the effect's last frame
+                    led.reset_effect_keys(color.r, color.g, color.b);
+                }
+                apply_profile_lighting(config, &new_profile, led);
 
                 if config.notify.0 {
                     // Send desktop notification
-                    let _ = std::process::Command::new("notify-send")
-                        .arg("-a")
-                        .arg("gkeys-rs")
-                        .arg(format!("Profile M{}", n))
-                        .spawn();
+                    command::notify(&format!("Profile M{}", n), "");
                 }
             }
         }
@@ -227,6 +549,7 @@ fn handle_event_for_recording(
     event: &Event,
     recorder: &mut Recorder,
     current_profile: &str,
+    macros: &MacroWorker,
     led: &LedController,
 ) -> Option<RecordingAction> {
     match event {
@@ -236,6 +559,11 @@ fn handle_event_for_recording(
                 log::debug!("MR event from LED write, ignoring");
                 return None;
             }
+            // MR while a macro is running cancels it instead of starting a recording
+            if !recorder.is_recording() && !recorder.is_awaiting() && macros.cancel_all() {
+                log::info!("MR pressed - cancelled running macro");
+                return None;
+            }
             let action = recorder.on_mr_press(current_profile);
             // Filter out None actions
             if matches!(action, RecordingAction::None) {
@@ -250,7 +578,13 @@ fn handle_event_for_recording(
 }
 
 /// Execute a recording action with LED control
-fn handle_recording_action(action: RecordingAction, config: &mut Config, led: &LedController) {
+fn handle_recording_action(
+    action: RecordingAction,
+    config: &mut Config,
+    config_path: &Path,
+    current_profile: &str,
+    led: &LedController,
+) {
     match action {
         RecordingAction::None => {}
 
@@ -268,14 +602,10 @@ fn handle_recording_action(action: RecordingAction, config: &mut Config, led: &L
             led.start_mr_flashing();
 
             log::debug!("Recording G{} - G-key red, MR flashing", gkey);
-            let _ = std::process::Command::new("notify-send")
-                .args([
-                    "-a",
-                    "gkeys-rs",
-                    &format!("Recording G{}", gkey),
-                    "Press keys, then MR to stop",
-                ])
-                .spawn();
+            command::notify(
+                &format!("Recording G{}", gkey),
+                "Press keys, then MR to stop",
+            );
         }
 
         RecordingAction::SaveMacro {
@@ -285,68 +615,54 @@ fn handle_recording_action(action: RecordingAction, config: &mut Config, led: &L
         } => {
             // Quick flash MR LED (handled by LED thread)
             led.quick_flash_mr(MR_QUICK_FLASH_COUNT);
-            // Restore G-key LEDs to configured color (or off if not set)
-            let gkey_color = config.rgb_color.as_ref().map(|c| (c.r, c.g, c.b));
-            led.restore_gkeys_color(gkey_color);
 
             // Save the macro
             let macro_name = format!("MACRO_{}", gkey);
-            config.set_macro(
-                &profile,
-                &macro_name,
-                Macro {
-                    hotkey_type: HotkeyType::Sequence,
-                    action: sequence.clone(),
-                },
-            );
-
-            if let Err(e) = config.save() {
-                log::error!("Failed to save config: {}", e);
-                let _ = std::process::Command::new("notify-send")
-                    .args([
-                        "-a",
-                        "gkeys-rs",
-                        "Recording failed",
-                        &format!("Could not save: {}", e),
-                    ])
-                    .spawn();
+            let result = config
+                .set_macro(
+                    &profile,
+                    &macro_name,
+                    Macro {
+                        hotkey_type: HotkeyType::Sequence,
+                        action: sequence.clone(),
+                        ..Default::default()
+                    },
+                )
+                .and_then(|()| config.save_to(config_path));
+            // After saving, so the highlight shows the new binding
+            restore_gkey_leds(config, &profile, led);
+
+            if let Err(e) = result {
+                log::error!("Failed to save macro: {:#}", e);
+                command::notify("Recording failed", &format!("Could not save: {}", e));
                 return;
             }
 
-            log::info!("Saved macro G{} = {}", gkey, sequence);
-            let _ = std::process::Command::new("notify-send")
-                .args(["-a", "gkeys-rs", &format!("Recorded G{}", gkey), &sequence])
-                .spawn();
+            log::info!("Saved macro G{} on profile {}", gkey, profile);
+            command::notify(&format!("Recorded G{}", gkey), &sequence);
         }
 
         RecordingAction::CancelledEmpty => {
             // No keys captured - just restore LEDs, no flash
             led.stop_mr_flashing();
-            let gkey_color = config.rgb_color.as_ref().map(|c| (c.r, c.g, c.b));
-            led.restore_gkeys_color(gkey_color);
+            restore_gkey_leds(config, current_profile, led);
             log::info!("Recording cancelled - no keys captured");
-            let _ = std::process::Command::new("notify-send")
-                .args(["-a", "gkeys-rs", "Recording cancelled", "No keys were captured"])
-                .spawn();
+            command::notify("Recording cancelled", "No keys were captured");
         }
 
         RecordingAction::CancelledNoGKey => {
             // MR pressed without G-key - just restore LEDs, no flash
             led.set_mr_led(false);
-            let gkey_color = config.rgb_color.as_ref().map(|c| (c.r, c.g, c.b));
-            led.restore_gkeys_color(gkey_color);
+            restore_gkey_leds(config, current_profile, led);
             log::debug!("Recording cancelled - no G-key selected");
         }
 
         RecordingAction::Error(msg) => {
             // Error - restore LEDs
             led.stop_mr_flashing();
-            let gkey_color = config.rgb_color.as_ref().map(|c| (c.r, c.g, c.b));
-            led.restore_gkeys_color(gkey_color);
+            restore_gkey_leds(config, current_profile, led);
             log::error!("Recording error: {}", msg);
-            let _ = std::process::Command::new("notify-send")
-                .args(["-a", "gkeys-rs", "Recording error", &msg])
-                .spawn();
+            command::notify("Recording error", &msg);
         }
     }
 }
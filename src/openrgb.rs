@@ -0,0 +1,481 @@
+//! Minimal OpenRGB SDK client
+//!
+//! When OpenRGB owns the keyboard lighting, the LED thread uses this to read
+//! the G-key colors before showing temporary feedback (recording, macro
+//! pulses) so they can be put back afterwards, and optionally to set the
+//! G-keys through OpenRGB instead of writing to the keyboard directly.
+//!
+//! Speaks protocol version 0, which every OpenRGB server accepts. Packets are
+//! a 16-byte header ("ORGB", device index, packet ID, data size, all u32
+//! little-endian) followed by the data.
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+
+use crate::config::{OpenRgbConfig, OpenRgbFeedback};
+
+const MAGIC: &[u8; 4] = b"ORGB";
+
+const REQUEST_CONTROLLER_COUNT: u32 = 0;
+const REQUEST_CONTROLLER_DATA: u32 = 1;
+const SET_CLIENT_NAME: u32 = 50;
+const UPDATE_SINGLE_LED: u32 = 1052;
+
+/// Timeout for connecting and for each reply, so a hung server can't stall
+/// the LED thread for long
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Wait between connection attempts while the server is unreachable
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A connection to an OpenRGB SDK server
+pub struct Client {
+    stream: TcpStream,
+}
+
+/// The parts of a controller's description gkeys-rs uses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Controller {
+    pub name: String,
+    pub leds: Vec<String>,
+    pub colors: Vec<(u8, u8, u8)>,
+}
+
+impl Client {
+    /// Connect and introduce ourselves
+    pub fn connect(host: &str, port: u16) -> Result<Self> {
+        let addr = (host, port)
+            .to_socket_addrs()?
+            .next()
+            .with_context(|| format!("Could not resolve {}", host))?;
+        let stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        let mut client = Self { stream };
+        client.send(0, SET_CLIENT_NAME, b"gkeys-rs\0")?;
+        Ok(client)
+    }
+
+    /// Number of controllers the server knows
+    pub fn controller_count(&mut self) -> Result<u32> {
+        self.send(0, REQUEST_CONTROLLER_COUNT, &[])?;
+        let data = self.receive(0, REQUEST_CONTROLLER_COUNT)?;
+        Reader::new(&data).u32()
+    }
+
+    /// Describe a controller, including its current colors
+    pub fn controller(&mut self, index: u32) -> Result<Controller> {
+        self.send(index, REQUEST_CONTROLLER_DATA, &[])?;
+        let data = self.receive(index, REQUEST_CONTROLLER_DATA)?;
+        parse_controller(&data).with_context(|| format!("Bad data for controller {}", index))
+    }
+
+    /// Set one LED of a controller
+    pub fn set_led(&mut self, controller: u32, led: u32, (r, g, b): (u8, u8, u8)) -> Result<()> {
+        let mut data = led.to_le_bytes().to_vec();
+        data.extend([r, g, b, 0]);
+        self.send(controller, UPDATE_SINGLE_LED, &data)
+    }
+
+    fn send(&mut self, device: u32, packet: u32, data: &[u8]) -> Result<()> {
+        let mut buf = Vec::with_capacity(16 + data.len());
+        buf.extend(MAGIC);
+        buf.extend(device.to_le_bytes());
+        buf.extend(packet.to_le_bytes());
+        buf.extend((data.len() as u32).to_le_bytes());
+        buf.extend(data);
+        self.stream.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Read the reply to a request, skipping unrelated notifications
+    fn receive(&mut self, device: u32, packet: u32) -> Result<Vec<u8>> {
+        loop {
+            let mut header = [0u8; 16];
+            self.stream.read_exact(&mut header)?;
+            if &header[..4] != MAGIC {
+                bail!("Bad packet header from OpenRGB");
+            }
+            let field = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+            let mut data = vec![0u8; field(12) as usize];
+            self.stream.read_exact(&mut data)?;
+            if field(4) == device && field(8) == packet {
+                return Ok(data);
+            }
+        }
+    }
+}
+
+/// Parse a protocol version 0 controller description
+fn parse_controller(data: &[u8]) -> Result<Controller> {
+    let mut r = Reader::new(data);
+    r.u32()?; // Data size
+    r.u32()?; // Device type
+    let name = r.string()?;
+    for _ in 0..4 {
+        r.string()?; // Description, version, serial, location
+    }
+
+    let modes = r.u16()?;
+    r.u32()?; // Active mode
+    for _ in 0..modes {
+        r.string()?;
+        // Value, flags, speed min/max, colors min/max, speed, direction, color mode
+        r.skip(9 * 4)?;
+        let colors = r.u16()?;
+        r.skip(usize::from(colors) * 4)?;
+    }
+
+    let zones = r.u16()?;
+    for _ in 0..zones {
+        r.string()?;
+        // Type, LEDs min/max/count
+        r.skip(4 * 4)?;
+        let matrix_len = r.u16()?;
+        r.skip(usize::from(matrix_len))?;
+    }
+
+    let led_count = r.u16()?;
+    let mut leds = Vec::with_capacity(usize::from(led_count));
+    for _ in 0..led_count {
+        leds.push(r.string()?);
+        r.u32()?; // Value
+    }
+
+    let color_count = r.u16()?;
+    let mut colors = Vec::with_capacity(usize::from(color_count));
+    for _ in 0..color_count {
+        let c = r.bytes(4)?;
+        colors.push((c[0], c[1], c[2]));
+    }
+
+    Ok(Controller { name, leds, colors })
+}
+
+/// Reads little-endian fields from a packet
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            bail!("Packet too short");
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    /// Length-prefixed, NUL-terminated string
+    fn string(&mut self) -> Result<String> {
+        let len = self.u16()?;
+        let bytes = self.bytes(usize::from(len))?;
+        let bytes = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+/// G-key number (1-5) for an OpenRGB LED name such as "Key: G1"
+fn gkey_of_led(name: &str) -> Option<u8> {
+    let name = name.strip_prefix("Key: ").unwrap_or(name);
+    let n = name.strip_prefix(['G', 'g'])?.parse().ok()?;
+    (1..=5).contains(&n).then_some(n)
+}
+
+/// The keyboard's G-keys as OpenRGB sees them, connecting on demand
+///
+/// Failures are logged and turn into `None`, so the LED thread carries on
+/// writing to the keyboard directly while OpenRGB isn't running.
+pub struct Link {
+    config: OpenRgbConfig,
+    client: Option<Client>,
+    /// Controller index and the LED index of G1-G5
+    gkeys: Option<(u32, [u32; 5])>,
+    last_attempt: Option<Instant>,
+}
+
+impl Link {
+    pub fn new(config: OpenRgbConfig) -> Self {
+        Self {
+            config,
+            client: None,
+            gkeys: None,
+            last_attempt: None,
+        }
+    }
+
+    pub fn config(&self) -> &OpenRgbConfig {
+        &self.config
+    }
+
+    /// Whether G-key colors should be sent through OpenRGB
+    pub fn sends(&self) -> bool {
+        self.config.feedback == OpenRgbFeedback::Send
+    }
+
+    /// Current colors of G1-G5
+    pub fn gkey_colors(&mut self) -> Option<[(u8, u8, u8); 5]> {
+        self.with_client(|client, index, leds| {
+            let controller = client.controller(index)?;
+            let color = |led: u32| controller.colors.get(led as usize).copied();
+            let colors = leds.map(color);
+            match colors.iter().all(Option::is_some) {
+                true => Ok(colors.map(Option::unwrap)),
+                false => bail!("Controller {} has fewer colors than LEDs", index),
+            }
+        })
+    }
+
+    /// Set G-keys through OpenRGB; false if that isn't possible right now
+    pub fn set_gkeys(&mut self, colors: &[(u8, (u8, u8, u8))]) -> bool {
+        self.with_client(|client, index, leds| {
+            for &(gkey, color) in colors {
+                client.set_led(index, leds[usize::from(gkey - 1)], color)?;
+            }
+            Ok(())
+        })
+        .is_some()
+    }
+
+    fn with_client<T>(
+        &mut self,
+        f: impl FnOnce(&mut Client, u32, [u32; 5]) -> Result<T>,
+    ) -> Option<T> {
+        if self.client.is_none() {
+            if self
+                .last_attempt
+                .is_some_and(|at| at.elapsed() < RETRY_INTERVAL)
+            {
+                return None;
+            }
+            self.last_attempt = Some(Instant::now());
+            match self.connect() {
+                Ok(()) => log::info!(
+                    "Connected to OpenRGB at {}:{}",
+                    self.config.host,
+                    self.config.port
+                ),
+                Err(e) => {
+                    log::debug!("OpenRGB not available: {:#}", e);
+                    return None;
+                }
+            }
+        }
+        let (index, leds) = self.gkeys?;
+        let client = self.client.as_mut()?;
+        match f(client, index, leds) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                log::warn!("Lost connection to OpenRGB: {:#}", e);
+                self.client = None;
+                self.gkeys = None;
+                None
+            }
+        }
+    }
+
+    /// Connect and find the controller with the G-keys
+    fn connect(&mut self) -> Result<()> {
+        let mut client = Client::connect(&self.config.host, self.config.port)?;
+        let wanted = self.config.device.as_deref().map(str::to_lowercase);
+        for index in 0..client.controller_count()? {
+            let controller = client.controller(index)?;
+            if let Some(ref wanted) = wanted {
+                if !controller.name.to_lowercase().contains(wanted) {
+                    continue;
+                }
+            }
+            let mut leds = [None; 5];
+            for (led, name) in controller.leds.iter().enumerate() {
+                if let Some(gkey) = gkey_of_led(name) {
+                    leds[usize::from(gkey - 1)] = Some(led as u32);
+                }
+            }
+            if leds.iter().all(Option::is_some) {
+                log::debug!("Using OpenRGB controller {} ({})", index, controller.name);
+                self.gkeys = Some((index, leds.map(Option::unwrap)));
+                self.client = Some(client);
+                return Ok(());
+            }
+        }
+        bail!("No OpenRGB controller with G-keys found")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn string(out: &mut Vec<u8>, s: &str) {
+        out.extend((s.len() as u16 + 1).to_le_bytes());
+        out.extend(s.as_bytes());
+        out.push(0);
+    }
+
+    /// Protocol version 0 description with one mode and one zone
+    fn controller_data(name: &str, leds: &[&str], colors: &[(u8, u8, u8)]) -> Vec<u8> {
+        let mut d = Vec::new();
+        d.extend(0u32.to_le_bytes()); // Device type
+        string(&mut d, name);
+        for s in ["desc", "1.0", "serial", "usb"] {
+            string(&mut d, s);
+        }
+        d.extend(1u16.to_le_bytes());
+        d.extend(0u32.to_le_bytes());
+        string(&mut d, "Direct");
+        d.extend([0u8; 9 * 4]);
+        d.extend(1u16.to_le_bytes());
+        d.extend([1, 2, 3, 0]);
+        d.extend(1u16.to_le_bytes());
+        string(&mut d, "Keyboard");
+        d.extend([0u8; 4 * 4]);
+        d.extend(12u16.to_le_bytes());
+        d.extend([0u8; 12]);
+        d.extend((leds.len() as u16).to_le_bytes());
+        for led in leds {
+            string(&mut d, led);
+            d.extend(0u32.to_le_bytes());
+        }
+        d.extend((colors.len() as u16).to_le_bytes());
+        for &(r, g, b) in colors {
+            d.extend([r, g, b, 0]);
+        }
+        let mut data = ((d.len() + 4) as u32).to_le_bytes().to_vec();
+        data.extend(d);
+        data
+    }
+
+    fn packet(device: u32, id: u32, data: &[u8]) -> Vec<u8> {
+        let mut p = MAGIC.to_vec();
+        for field in [device, id, data.len() as u32] {
+            p.extend(field.to_le_bytes());
+        }
+        p.extend(data);
+        p
+    }
+
+    /// Fake SDK server with a mouse and a keyboard; reports each request
+    fn fake_server() -> (u16, mpsc::Receiver<(u32, u32, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mouse = controller_data("Mouse", &["Logo"], &[(1, 1, 1)]);
+            let gkeys = ["Key: G1", "Key: G2", "Key: G3", "Key: G4", "Key: G5"];
+            let mut leds = vec!["Key: Escape"];
+            leds.extend(gkeys);
+            let colors = [
+                (9, 9, 9),
+                (10, 0, 0),
+                (20, 0, 0),
+                (30, 0, 0),
+                (40, 0, 0),
+                (50, 0, 0),
+            ];
+            let keyboard = controller_data("Logitech G815", &leds, &colors);
+            loop {
+                let mut header = [0u8; 16];
+                if stream.read_exact(&mut header).is_err() {
+                    return;
+                }
+                let field = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+                let (device, id) = (field(4), field(8));
+                let mut data = vec![0u8; field(12) as usize];
+                stream.read_exact(&mut data).unwrap();
+                let reply = match id {
+                    // A notification before the reply, which the client skips
+                    REQUEST_CONTROLLER_COUNT => {
+                        [packet(0, 100, &[]), packet(0, id, &2u32.to_le_bytes())].concat()
+                    }
+                    REQUEST_CONTROLLER_DATA if device == 0 => packet(0, id, &mouse),
+                    REQUEST_CONTROLLER_DATA => packet(1, id, &keyboard),
+                    _ => Vec::new(),
+                };
+                stream.write_all(&reply).unwrap();
+                tx.send((device, id, data)).unwrap();
+            }
+        });
+        (port, rx)
+    }
+
+    #[test]
+    fn test_link() {
+        let (port, requests) = fake_server();
+        let mut link = Link::new(OpenRgbConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            device: None,
+            feedback: OpenRgbFeedback::Send,
+        });
+        assert!(link.sends());
+        assert_eq!(
+            link.gkey_colors(),
+            Some([(10, 0, 0), (20, 0, 0), (30, 0, 0), (40, 0, 0), (50, 0, 0)])
+        );
+        assert_eq!(
+            requests.recv().unwrap(),
+            (0, SET_CLIENT_NAME, b"gkeys-rs\0".to_vec())
+        );
+        assert_eq!(requests.recv().unwrap().1, REQUEST_CONTROLLER_COUNT);
+        assert_eq!(requests.recv().unwrap().0, 0);
+        assert_eq!(requests.recv().unwrap().0, 1);
+        assert_eq!(requests.recv().unwrap().0, 1);
+
+        // G3 is the fourth LED
+        assert!(link.set_gkeys(&[(3, (1, 2, 3))]));
+        let (device, id, data) = requests.recv().unwrap();
+        assert_eq!((device, id), (1, UPDATE_SINGLE_LED));
+        assert_eq!(data, [3, 0, 0, 0, 1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn test_unavailable() {
+        // Nothing listens on a port that was just freed
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut link = Link::new(OpenRgbConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            device: None,
+            feedback: OpenRgbFeedback::Restore,
+        });
+        assert_eq!(link.gkey_colors(), None);
+        assert!(!link.set_gkeys(&[(1, (0, 0, 0))]));
+    }
+
+    #[test]
+    fn test_gkey_of_led() {
+        assert_eq!(gkey_of_led("Key: G1"), Some(1));
+        assert_eq!(gkey_of_led("g5"), Some(5));
+        assert_eq!(gkey_of_led("Key: G6"), None);
+        assert_eq!(gkey_of_led("Key: Escape"), None);
+    }
+}
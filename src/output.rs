@@ -0,0 +1,172 @@
+//! Output backends for virtual key and pointer events
+//!
+//! [`VirtualKeyboard`](crate::uinput::VirtualKeyboard) turns macros into
+//! [`OutputEvent`]s and hands them to an [`OutputBackend`]: uinput devices
+//! in normal operation, a logger for `--dry-run`, and an in-memory recorder
+//! in tests.
+
+use anyhow::{Context, Result};
+use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+use evdev::{AttributeSet, EventType, InputEvent, Key, RelativeAxisType};
+
+use crate::uinput::{keys, MOUSE_BUTTONS};
+
+/// A single key or pointer event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputEvent {
+    /// Key or button code, 1 for press and 0 for release
+    Key(u16, i32),
+    /// Pointer motion along X/Y
+    Move(i32, i32),
+    /// Wheel clicks along X/Y
+    Scroll(i32, i32),
+}
+
+impl OutputEvent {
+    /// Whether the event belongs to the virtual mouse
+    pub fn is_mouse(&self) -> bool {
+        match self {
+            OutputEvent::Key(code, _) => MOUSE_BUTTONS.contains(code),
+            OutputEvent::Move(..) | OutputEvent::Scroll(..) => true,
+        }
+    }
+}
+
+/// Destination for events produced by macros
+pub trait OutputBackend: Send {
+    /// Send one event as a complete input frame (followed by SYN_REPORT)
+    fn emit(&mut self, event: OutputEvent) -> Result<()>;
+
+    /// Whether events reach a real device. When false, delays are skipped
+    /// and helpers such as the clipboard command are not run.
+    fn is_live(&self) -> bool {
+        true
+    }
+}
+
+/// Key codes enabled on the virtual keyboard: every key and button code, so
+/// media keys, numeric codes and `BTN_*` names all work
+pub fn device_keys() -> impl Iterator<Item = u16> {
+    1..=keys::KEY_MAX
+}
+
+/// Virtual keyboard and mouse created through /dev/uinput
+///
+/// The mouse is a separate device so udev classifies it as a plain mouse.
+/// Both devices are destroyed when the backend is dropped.
+pub struct UinputBackend {
+    keyboard: VirtualDevice,
+    mouse: VirtualDevice,
+}
+
+impl UinputBackend {
+    pub fn new() -> Result<Self> {
+        let mut key_set = AttributeSet::<Key>::new();
+        for code in device_keys() {
+            key_set.insert(Key::new(code));
+        }
+        let keyboard = VirtualDeviceBuilder::new()
+            .context("Failed to open /dev/uinput")?
+            .name("gkeys-rs virtual keyboard")
+            .with_keys(&key_set)
+            .context("Failed to enable keys on virtual keyboard")?
+            .build()
+            .context("Failed to create virtual keyboard")?;
+
+        let mut button_set = AttributeSet::<Key>::new();
+        for code in MOUSE_BUTTONS {
+            button_set.insert(Key::new(code));
+        }
+        let mut axes = AttributeSet::<RelativeAxisType>::new();
+        for axis in [
+            RelativeAxisType::REL_X,
+            RelativeAxisType::REL_Y,
+            RelativeAxisType::REL_WHEEL,
+            RelativeAxisType::REL_HWHEEL,
+        ] {
+            axes.insert(axis);
+        }
+        let mouse = VirtualDeviceBuilder::new()
+            .context("Failed to open /dev/uinput")?
+            .name("gkeys-rs virtual mouse")
+            .with_keys(&button_set)
+            .context("Failed to enable buttons on virtual mouse")?
+            .with_relative_axes(&axes)
+            .context("Failed to enable axes on virtual mouse")?
+            .build()
+            .context("Failed to create virtual mouse")?;
+
+        // Give udev time to create the device nodes
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        Ok(Self { keyboard, mouse })
+    }
+}
+
+impl OutputBackend for UinputBackend {
+    fn emit(&mut self, event: OutputEvent) -> Result<()> {
+        let events = match event {
+            OutputEvent::Key(code, value) => vec![InputEvent::new(EventType::KEY, code, value)],
+            OutputEvent::Move(dx, dy) => vec![
+                InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_X.0, dx),
+                InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_Y.0, dy),
+            ],
+            OutputEvent::Scroll(dx, dy) => [
+                (RelativeAxisType::REL_WHEEL, dy),
+                (RelativeAxisType::REL_HWHEEL, dx),
+            ]
+            .into_iter()
+            .filter(|&(_, clicks)| clicks != 0)
+            .map(|(axis, clicks)| InputEvent::new(EventType::RELATIVE, axis.0, clicks))
+            .collect(),
+        };
+        let device = if event.is_mouse() {
+            &mut self.mouse
+        } else {
+            &mut self.keyboard
+        };
+        // VirtualDevice::emit terminates the batch with SYN_REPORT
+        device.emit(&events).context("Failed to write to uinput")
+    }
+}
+
+/// Logs events instead of sending them, for `--dry-run`
+pub struct DryRunBackend;
+
+impl OutputBackend for DryRunBackend {
+    fn emit(&mut self, event: OutputEvent) -> Result<()> {
+        log::trace!("Dry run: {:?}", event);
+        Ok(())
+    }
+
+    fn is_live(&self) -> bool {
+        false
+    }
+}
+
+/// Keeps every event in memory so tests can assert the exact stream
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct RecordingBackend {
+    events: std::sync::Arc<std::sync::Mutex<Vec<OutputEvent>>>,
+}
+
+#[cfg(test)]
+impl RecordingBackend {
+    /// Events emitted so far
+    pub fn events(&self) -> Vec<OutputEvent> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl OutputBackend for RecordingBackend {
+    fn emit(&mut self, event: OutputEvent) -> Result<()> {
+        self.events.lock().unwrap().push(event);
+        Ok(())
+    }
+
+    fn is_live(&self) -> bool {
+        false
+    }
+}
@@ -55,15 +55,23 @@ pub enum RecordingAction {
 /// Recorder manages the macro recording state machine
 pub struct Recorder {
     state: RecordingState,
+    /// Set while a secret macro types; captured keys are dropped meanwhile
+    secret_active: Arc<AtomicBool>,
 }
 
 impl Recorder {
     pub fn new() -> Self {
         Self {
             state: RecordingState::Idle,
+            secret_active: Arc::new(AtomicBool::new(false)),
         }
     }
 
+    /// Share the macro executor's secret flag so secret text is never recorded
+    pub fn set_secret_flag(&mut self, flag: Arc<AtomicBool>) {
+        self.secret_active = flag;
+    }
+
     /// Check if currently recording
     pub fn is_recording(&self) -> bool {
         matches!(self.state, RecordingState::Recording { .. })
@@ -114,10 +122,9 @@ impl Recorder {
                 // Build the sequence string
                 let sequence = captured_keys.join(", ");
                 log::info!(
-                    "Recording: finished G{} with {} keys: {}",
+                    "Recording: finished G{} with {} keys",
                     gkey,
-                    captured_keys.len(),
-                    sequence
+                    captured_keys.len()
                 );
 
                 // If no keys captured, return CancelledEmpty instead of SaveMacro
@@ -142,7 +149,7 @@ impl Recorder {
         if let RecordingState::AwaitingGKey { profile } = old_state {
             // Try to find and open the keyboard evdev device
             match find_keyboard_evdev() {
-                Some(path) => match start_capture_thread(&path) {
+                Some(path) => match start_capture_thread(&path, self.secret_active.clone()) {
                     Ok((receiver, stop_flag, handle)) => {
                         self.state = RecordingState::Recording {
                             profile: profile.clone(),
@@ -183,7 +190,6 @@ impl Recorder {
         {
             // Non-blocking receive of captured keys
             while let Ok(key) = receiver.try_recv() {
-                log::debug!("Recording: captured key '{}'", key);
                 captured_keys.push(key);
             }
         }
@@ -197,7 +203,7 @@ impl Default for Recorder {
 }
 
 /// Find the G815 keyboard evdev device (interface 0, standard keyboard)
-fn find_keyboard_evdev() -> Option<PathBuf> {
+pub fn find_keyboard_evdev() -> Option<PathBuf> {
     let devices = evdev::enumerate();
     for (path, device) in devices {
         let id = device.input_id();
@@ -234,6 +240,7 @@ fn find_keyboard_evdev() -> Option<PathBuf> {
 /// Start the keyboard capture thread
 fn start_capture_thread(
     path: &PathBuf,
+    secret_active: Arc<AtomicBool>,
 ) -> Result<(Receiver<String>, Arc<AtomicBool>, JoinHandle<()>)> {
     let device = EvdevDevice::open(path)?;
     let (tx, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
@@ -241,14 +248,21 @@ fn start_capture_thread(
     let stop_flag_clone = stop_flag.clone();
 
     let handle = thread::spawn(move || {
-        capture_loop(device, tx, stop_flag_clone);
+        capture_loop(device, tx, stop_flag_clone, secret_active);
     });
 
     Ok((rx, stop_flag, handle))
 }
 
 /// Main capture loop running in separate thread
-fn capture_loop(mut device: EvdevDevice, tx: Sender<String>, stop_flag: Arc<AtomicBool>) {
+///
+/// Key names are never logged: a recording may contain passwords.
+fn capture_loop(
+    mut device: EvdevDevice,
+    tx: Sender<String>,
+    stop_flag: Arc<AtomicBool>,
+    secret_active: Arc<AtomicBool>,
+) {
     let mut modifier_state = ModifierState::default();
     log::debug!("Recording: capture thread started");
 
@@ -262,24 +276,24 @@ fn capture_loop(mut device: EvdevDevice, tx: Sender<String>, stop_flag: Arc<Atom
         match device.fetch_events() {
             Ok(events) => {
                 for event in events {
-                    log::trace!("Recording: raw event {:?}", event);
                     if let InputEventKind::Key(key) = event.kind() {
                         let pressed = event.value() == 1; // 1 = press, 0 = release, 2 = repeat
-                        log::debug!(
-                            "Recording: key event {:?} pressed={}",
-                            key,
-                            pressed
-                        );
 
                         // Update modifier state
                         if modifier_state.update(key, pressed) {
                             continue; // Don't emit modifier keys themselves
                         }
 
+                        // Skip keys while a secret macro is typing
+                        if pressed && secret_active.load(Ordering::SeqCst) {
+                            log::debug!("Recording: skipped key during secret macro");
+                            continue;
+                        }
+
                         // Only capture key presses, not releases or repeats
                         if pressed {
                             if let Some(key_str) = modifier_state.format_with_key(key) {
-                                log::info!("Recording: captured '{}'", key_str);
+                                log::debug!("Recording: captured a key");
                                 if tx.send(key_str).is_err() {
                                     // Receiver dropped, exit
                                     log::debug!("Recording: receiver dropped, exiting");
@@ -478,10 +492,7 @@ fn key_to_name(key: Key) -> Option<&'static str> {
         | Key::KEY_LEFTMETA
         | Key::KEY_RIGHTMETA => return None,
         // Unknown key
-        _ => {
-            log::trace!("Unknown key: {:?}", key);
-            return None;
-        }
+        _ => return None,
     })
 }
 
@@ -0,0 +1,156 @@
+//! Secrets for typeout macros
+//!
+//! A secret macro reads its text when the G-key is pressed, from a private
+//! file, an environment variable or a command, instead of keeping it in
+//! config.json. The text is never logged, and the copy that is typed is
+//! zeroed after use. Buffers it passes through on the way (file and pipe
+//! reads) are not.
+
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::command;
+use crate::config::RunOptions;
+
+/// Where a secret macro gets its text
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretSource {
+    /// A file only the current user can read (mode 0600 or stricter)
+    File(PathBuf),
+    /// An environment variable of the daemon
+    Env(String),
+    /// Standard output of a shell command
+    Command(String),
+}
+
+/// Secret text, zeroed when dropped and hidden from `Debug`
+pub struct Secret(String);
+
+impl Secret {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Move the text out, leaving nothing behind to zero
+    fn take(mut self) -> String {
+        std::mem::take(&mut self.0)
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        // SAFETY: zero bytes are valid UTF-8
+        unsafe { self.0.as_bytes_mut().fill(0) };
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(<redacted>)")
+    }
+}
+
+impl SecretSource {
+    /// Read the secret, without its trailing newline
+    ///
+    /// `options` (env, cwd), `cancelled` and `timeout` apply to command sources.
+    pub fn resolve(
+        &self,
+        options: &RunOptions,
+        timeout: Duration,
+        cancelled: impl Fn() -> bool,
+    ) -> Result<Secret> {
+        let mut secret = Secret(match self {
+            SecretSource::File(path) => read_private_file(path)?,
+            SecretSource::Env(name) => std::env::var(name)
+                .with_context(|| format!("Secret environment variable {} is not set", name))?,
+            SecretSource::Command(cmd) => {
+                let cmd = command::build(cmd, options)?;
+                let (status, output) = command::run_captured(cmd, Some(timeout), cancelled)
+                    .context("Secret command failed")?;
+                let output = Secret(output);
+                if !status.success() {
+                    bail!("Secret command exited with {}", command::describe(status));
+                }
+                output.take()
+            }
+        });
+        let len = secret.0.trim_end_matches(['\r', '\n']).len();
+        // SAFETY: zero bytes are valid UTF-8
+        unsafe { secret.0.as_bytes_mut()[len..].fill(0) };
+        secret.0.truncate(len);
+        Ok(secret)
+    }
+}
+
+/// Read a secret file, refusing files other users could read or write
+fn read_private_file(path: &Path) -> Result<String> {
+    let path = command::expand_home(path)?;
+    let meta = fs::metadata(&path)
+        .with_context(|| format!("Failed to read secret file {}", path.display()))?;
+    if meta.mode() & 0o077 != 0 {
+        bail!(
+            "Secret file {} has mode {:o}; run `chmod 600` on it",
+            path.display(),
+            meta.mode() & 0o777
+        );
+    }
+    // SAFETY: getuid has no preconditions and cannot fail
+    if meta.uid() != unsafe { libc::getuid() } {
+        bail!(
+            "Secret file {} is not owned by the current user",
+            path.display()
+        );
+    }
+    fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read secret file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_file_permissions() {
+        let path = std::env::temp_dir().join(format!("gkeys-rs-secret-{}", std::process::id()));
+        fs::write(&path, "hunter2\n").unwrap();
+        let source = SecretSource::File(path.clone());
+        let options = RunOptions::default();
+        let resolve = || source.resolve(&options, Duration::from_secs(1), || false);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let err = resolve().unwrap_err();
+        assert!(err.to_string().contains("chmod 600"));
+        assert!(!format!("{:#}", err).contains("hunter2"));
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(resolve().unwrap().as_str(), "hunter2");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_env_and_command() {
+        let source = SecretSource::Command("printf '%s\\n' \"$GKEYS_SECRET\"".into());
+        let options = RunOptions {
+            env: [("GKEYS_SECRET".to_string(), "s3cret".to_string())].into(),
+            ..Default::default()
+        };
+        let secret = source
+            .resolve(&options, Duration::from_secs(5), || false)
+            .unwrap();
+        assert_eq!(secret.as_str(), "s3cret");
+        assert_eq!(format!("{:?}", secret), "Secret(<redacted>)");
+
+        let source = SecretSource::Env("GKEYS_RS_TEST_UNSET_SECRET".into());
+        let result = source.resolve(&options, Duration::from_secs(1), || false);
+        assert!(result.is_err());
+    }
+}
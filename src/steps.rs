@@ -0,0 +1,289 @@
+//! Macro step DSL
+//!
+//! Macros are compiled at config load into a list of [`Action`]s with key
+//! names already resolved, so typos are reported before a G-key is pressed.
+//! `shortcut`, `sequence` and `uinput` strings are sugar for step lists.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::uinput::VirtualKeyboard;
+
+/// A macro step as written in the config
+///
+/// ```json
+/// [
+///   { "press": "shift" },
+///   { "repeat": { "times": 5, "steps": [ { "tap": "down" } ] } },
+///   { "release": "shift" },
+///   { "chord": "ctrl+c" },
+///   { "wait": 100 },
+///   { "text": "done" },
+///   { "move": { "x": 100, "y": -50 } },
+///   { "click": "left" },
+///   { "scroll": { "y": -3 } }
+/// ]
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Step {
+    /// Press and hold a key
+    Press(String),
+    /// Release a held key
+    Release(String),
+    /// Press and release a key
+    Tap(String),
+    /// Press keys together, then release them in reverse ("ctrl+shift+t")
+    Chord(String),
+    /// Type literal text using the keyboard layout
+    Text(String),
+    /// Wait for a number of milliseconds
+    Wait(u64),
+    /// Click a mouse button ("left", "right", "middle", "side", "extra")
+    Click(String),
+    /// Move the mouse pointer by a relative amount
+    Move {
+        #[serde(default)]
+        x: i32,
+        #[serde(default)]
+        y: i32,
+    },
+    /// Turn the mouse wheel by a number of clicks (positive `y` is up)
+    Scroll {
+        #[serde(default)]
+        x: i32,
+        #[serde(default)]
+        y: i32,
+    },
+    /// Run nested steps several times
+    Repeat { times: u32, steps: Vec<Step> },
+}
+
+/// A compiled step with key codes resolved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Press(u16),
+    Release(u16),
+    Tap(u16),
+    Chord(Vec<u16>),
+    Text(String),
+    Wait(Duration),
+    Move(i32, i32),
+    Scroll(i32, i32),
+    Repeat(u32, Vec<Action>),
+}
+
+/// Compile a step list, checking key names and that every pressed key is released
+pub fn compile(steps: &[Step]) -> Result<Vec<Action>> {
+    let mut held = Vec::new();
+    let actions = compile_block(steps, &mut held)?;
+    if let Some((_, name)) = held.first() {
+        bail!("Key '{}' is pressed but never released", name);
+    }
+    Ok(actions)
+}
+
+/// `held` tracks pressed keys by code, with the name used for errors, so
+/// aliases like "ctrl" and "KEY_LEFTCTRL" pair up
+fn compile_block(steps: &[Step], held: &mut Vec<(u16, String)>) -> Result<Vec<Action>> {
+    steps
+        .iter()
+        .map(|step| {
+            Ok(match step {
+                Step::Press(name) => {
+                    let key = parse_key(name)?;
+                    held.push((key, name.trim().to_string()));
+                    Action::Press(key)
+                }
+                Step::Release(name) => {
+                    let key = parse_key(name)?;
+                    let Some(pos) = held.iter().position(|&(code, _)| code == key) else {
+                        bail!("Key '{}' is released but was not pressed", name);
+                    };
+                    held.remove(pos);
+                    Action::Release(key)
+                }
+                Step::Tap(name) => Action::Tap(parse_key(name)?),
+                Step::Chord(chord) => Action::Chord(parse_chord(chord)?),
+                Step::Text(text) => Action::Text(text.clone()),
+                Step::Wait(ms) => Action::Wait(Duration::from_millis(*ms)),
+                Step::Click(name) => Action::Tap(
+                    VirtualKeyboard::parse_button(name)
+                        .with_context(|| format!("Unknown mouse button '{}'", name.trim()))?,
+                ),
+                Step::Move { x, y } => Action::Move(*x, *y),
+                Step::Scroll { x, y } => Action::Scroll(*x, *y),
+                Step::Repeat { times, steps } => {
+                    if *times == 0 {
+                        bail!("Repeat count must be at least 1");
+                    }
+                    // Repeated blocks must release whatever they press
+                    let mut inner_held = Vec::new();
+                    let inner = compile_block(steps, &mut inner_held)?;
+                    if let Some((_, name)) = inner_held.first() {
+                        bail!("Key '{}' is pressed but never released inside repeat", name);
+                    }
+                    Action::Repeat(*times, inner)
+                }
+            })
+        })
+        .collect()
+}
+
+/// Compile a shortcut string like "ctrl+shift+t"; an empty one does nothing
+pub fn compile_shortcut(shortcut: &str) -> Result<Vec<Action>> {
+    if shortcut.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(vec![Action::Chord(parse_chord(shortcut)?)])
+}
+
+/// Compile a sequence string like "ctrl+a, ctrl+c"
+pub fn compile_sequence(seq: &str) -> Result<Vec<Action>> {
+    seq.split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(|part| Ok(Action::Chord(parse_chord(part)?)))
+        .collect()
+}
+
+/// Compile a uinput key name like "KEY_F13"
+pub fn compile_uinput(key_name: &str) -> Result<Vec<Action>> {
+    Ok(vec![Action::Tap(parse_key(key_name)?)])
+}
+
+fn parse_key(name: &str) -> Result<u16> {
+    VirtualKeyboard::parse_key(name).with_context(|| format!("Unknown key '{}'", name.trim()))
+}
+
+fn parse_chord(chord: &str) -> Result<Vec<u16>> {
+    if chord.trim().is_empty() {
+        bail!("Empty key chord");
+    }
+    chord.split('+').map(parse_key).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uinput::keys;
+
+    #[test]
+    fn test_compile_steps() {
+        let steps: Vec<Step> = serde_json::from_str(
+            r#"[
+                { "press": "shift" },
+                { "repeat": { "times": 5, "steps": [ { "tap": "down" } ] } },
+                { "release": "Shift" },
+                { "chord": "ctrl+c" },
+                { "wait": 100 },
+                { "text": "done" }
+            ]"#,
+        )
+        .unwrap();
+
+        let actions = compile(&steps).unwrap();
+        assert_eq!(
+            actions,
+            vec![
+                Action::Press(keys::KEY_LEFTSHIFT),
+                Action::Repeat(5, vec![Action::Tap(keys::KEY_DOWN)]),
+                Action::Release(keys::KEY_LEFTSHIFT),
+                Action::Chord(vec![keys::KEY_LEFTCTRL, keys::KEY_C]),
+                Action::Wait(Duration::from_millis(100)),
+                Action::Text("done".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_release_by_other_name() {
+        // Presses and releases pair up by key, whatever name each one uses
+        for release in ["control", "KEY_LEFTCTRL", "29"] {
+            let steps = vec![Step::Press("ctrl".into()), Step::Release(release.into())];
+            assert_eq!(
+                compile(&steps).unwrap(),
+                vec![
+                    Action::Press(keys::KEY_LEFTCTRL),
+                    Action::Release(keys::KEY_LEFTCTRL),
+                ],
+                "{}",
+                release
+            );
+        }
+        let steps = vec![
+            Step::Press("ctrl".into()),
+            Step::Release("KEY_RIGHTCTRL".into()),
+        ];
+        assert!(compile(&steps).is_err());
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let unknown = vec![Step::Tap("nosuchkey".into())];
+        assert!(compile(&unknown).is_err());
+
+        let unreleased = vec![Step::Press("ctrl".into()), Step::Tap("a".into())];
+        assert!(compile(&unreleased).is_err());
+
+        let unpressed = vec![Step::Release("ctrl".into())];
+        assert!(compile(&unpressed).is_err());
+
+        let bad_button = vec![Step::Click("enter".into())];
+        assert!(compile(&bad_button).is_err());
+
+        let zero_repeat = vec![Step::Repeat {
+            times: 0,
+            steps: vec![],
+        }];
+        assert!(compile(&zero_repeat).is_err());
+    }
+
+    #[test]
+    fn test_mouse_steps() {
+        let steps: Vec<Step> = serde_json::from_str(
+            r#"[
+                { "move": { "x": 100, "y": -50 } },
+                { "click": "left" },
+                { "press": "btn_right" },
+                { "release": "btn_right" },
+                { "scroll": { "y": -3 } }
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            compile(&steps).unwrap(),
+            vec![
+                Action::Move(100, -50),
+                Action::Tap(keys::BTN_LEFT),
+                Action::Press(keys::BTN_RIGHT),
+                Action::Release(keys::BTN_RIGHT),
+                Action::Scroll(0, -3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sugar() {
+        assert_eq!(
+            compile_sequence("ctrl+a, ctrl+c").unwrap(),
+            vec![
+                Action::Chord(vec![keys::KEY_LEFTCTRL, keys::KEY_A]),
+                Action::Chord(vec![keys::KEY_LEFTCTRL, keys::KEY_C]),
+            ]
+        );
+        assert_eq!(
+            compile_uinput("KEY_ENTER").unwrap(),
+            vec![Action::Tap(keys::KEY_ENTER)]
+        );
+        assert_eq!(
+            compile_shortcut("shift+micmute").unwrap(),
+            vec![Action::Chord(vec![keys::KEY_LEFTSHIFT, keys::KEY_MICMUTE])]
+        );
+        assert!(compile_shortcut("ctrl+").is_err());
+        assert_eq!(compile_shortcut(" ").unwrap(), vec![]);
+    }
+}
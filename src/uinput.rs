@@ -1,52 +1,20 @@
-//! Virtual keyboard using uinput for key emission
+//! Virtual keyboard and mouse: turns typeout, shortcuts and steps into key
+//! and pointer events for an [`OutputBackend`]
 
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
 use std::io::Write;
-use std::mem;
-use std::os::unix::io::AsRawFd;
-use std::sync::LazyLock;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{Context, Result};
 
-// Linux input event types and codes
-const EV_SYN: u16 = 0x00;
-const EV_KEY: u16 = 0x01;
-const SYN_REPORT: u16 = 0x00;
-
-// uinput ioctl commands
-const UI_SET_EVBIT: libc::c_ulong = 0x40045564;
-const UI_SET_KEYBIT: libc::c_ulong = 0x40045565;
-const UI_DEV_SETUP: libc::c_ulong = 0x405c5503;
-const UI_DEV_CREATE: libc::c_ulong = 0x5501;
-const UI_DEV_DESTROY: libc::c_ulong = 0x5502;
-
-const BUS_USB: u16 = 0x03;
-
-/// input_event structure for writing events
-#[repr(C)]
-struct InputEvent {
-    time: libc::timeval,
-    type_: u16,
-    code: u16,
-    value: i32,
-}
-
-/// uinput_setup structure for device setup
-#[repr(C)]
-struct UinputSetup {
-    id: InputId,
-    name: [u8; 80],
-    ff_effects_max: u32,
-}
-
-#[repr(C)]
-struct InputId {
-    bustype: u16,
-    vendor: u16,
-    product: u16,
-    version: u16,
-}
+use crate::config::{ClipboardConfig, Fallback, Typing};
+use crate::keycodes;
+use crate::layout::{self, KeyStroke, Layout};
+use crate::output::{DryRunBackend, OutputBackend, OutputEvent, UinputBackend};
+use crate::steps::Action;
 
 /// Key code definitions (subset of linux/input-event-codes.h)
 pub mod keys {
@@ -105,6 +73,7 @@ pub mod keys {
     pub const KEY_SLASH: u16 = 53;
     #[allow(dead_code)]
     pub const KEY_RIGHTSHIFT: u16 = 54;
+    pub const KEY_KPASTERISK: u16 = 55;
     pub const KEY_LEFTALT: u16 = 56;
     pub const KEY_SPACE: u16 = 57;
     pub const KEY_CAPSLOCK: u16 = 58;
@@ -118,6 +87,22 @@ pub mod keys {
     pub const KEY_F8: u16 = 66;
     pub const KEY_F9: u16 = 67;
     pub const KEY_F10: u16 = 68;
+    pub const KEY_NUMLOCK: u16 = 69;
+    pub const KEY_SCROLLLOCK: u16 = 70;
+    pub const KEY_KP7: u16 = 71;
+    pub const KEY_KP8: u16 = 72;
+    pub const KEY_KP9: u16 = 73;
+    pub const KEY_KPMINUS: u16 = 74;
+    pub const KEY_KP4: u16 = 75;
+    pub const KEY_KP5: u16 = 76;
+    pub const KEY_KP6: u16 = 77;
+    pub const KEY_KPPLUS: u16 = 78;
+    pub const KEY_KP1: u16 = 79;
+    pub const KEY_KP2: u16 = 80;
+    pub const KEY_KP3: u16 = 81;
+    pub const KEY_KP0: u16 = 82;
+    pub const KEY_KPDOT: u16 = 83;
+    pub const KEY_102ND: u16 = 86;
     pub const KEY_F11: u16 = 87;
     pub const KEY_F12: u16 = 88;
     pub const KEY_F13: u16 = 183;
@@ -128,9 +113,11 @@ pub mod keys {
     pub const KEY_F18: u16 = 188;
     pub const KEY_F19: u16 = 189;
     pub const KEY_F20: u16 = 190;
+    pub const KEY_KPENTER: u16 = 96;
     #[allow(dead_code)]
     pub const KEY_RIGHTCTRL: u16 = 97;
-    #[allow(dead_code)]
+    pub const KEY_KPSLASH: u16 = 98;
+    pub const KEY_SYSRQ: u16 = 99;
     pub const KEY_RIGHTALT: u16 = 100;
     pub const KEY_HOME: u16 = 102;
     pub const KEY_UP: u16 = 103;
@@ -142,12 +129,42 @@ pub mod keys {
     pub const KEY_PAGEDOWN: u16 = 109;
     pub const KEY_INSERT: u16 = 110;
     pub const KEY_DELETE: u16 = 111;
+    pub const KEY_MUTE: u16 = 113;
+    pub const KEY_VOLUMEDOWN: u16 = 114;
+    pub const KEY_VOLUMEUP: u16 = 115;
+    pub const KEY_PAUSE: u16 = 119;
     pub const KEY_LEFTMETA: u16 = 125;
     #[allow(dead_code)]
     pub const KEY_RIGHTMETA: u16 = 126;
+    pub const KEY_COMPOSE: u16 = 127;
+    pub const KEY_CALC: u16 = 140;
+    pub const KEY_MAIL: u16 = 155;
+    pub const KEY_NEXTSONG: u16 = 163;
+    pub const KEY_PLAYPAUSE: u16 = 164;
+    pub const KEY_PREVIOUSSONG: u16 = 165;
+    pub const KEY_STOPCD: u16 = 166;
+    pub const KEY_HOMEPAGE: u16 = 172;
+    pub const KEY_PLAY: u16 = 207;
+    pub const KEY_BRIGHTNESSDOWN: u16 = 224;
+    pub const KEY_BRIGHTNESSUP: u16 = 225;
+    pub const KEY_MICMUTE: u16 = 248;
+    pub const BTN_LEFT: u16 = 0x110;
+    pub const BTN_RIGHT: u16 = 0x111;
+    pub const BTN_MIDDLE: u16 = 0x112;
+    pub const BTN_SIDE: u16 = 0x113;
+    pub const BTN_EXTRA: u16 = 0x114;
     pub const KEY_MAX: u16 = 0x2ff;
 }
 
+/// Buttons sent through the virtual mouse rather than the keyboard
+pub const MOUSE_BUTTONS: [u16; 5] = [
+    keys::BTN_LEFT,
+    keys::BTN_RIGHT,
+    keys::BTN_MIDDLE,
+    keys::BTN_SIDE,
+    keys::BTN_EXTRA,
+];
+
 /// Map of key names to key codes
 static KEY_MAP: LazyLock<HashMap<&'static str, u16>> = LazyLock::new(|| {
     let mut m = HashMap::new();
@@ -241,6 +258,46 @@ static KEY_MAP: LazyLock<HashMap<&'static str, u16>> = LazyLock::new(|| {
     m.insert("left", keys::KEY_LEFT);
     m.insert("right", keys::KEY_RIGHT);
     m.insert("capslock", keys::KEY_CAPSLOCK);
+    m.insert("numlock", keys::KEY_NUMLOCK);
+    m.insert("scrolllock", keys::KEY_SCROLLLOCK);
+    m.insert("printscreen", keys::KEY_SYSRQ);
+    m.insert("sysrq", keys::KEY_SYSRQ);
+    m.insert("pause", keys::KEY_PAUSE);
+
+    // Numpad (names match those produced by macro recording)
+    m.insert("kp0", keys::KEY_KP0);
+    m.insert("kp1", keys::KEY_KP1);
+    m.insert("kp2", keys::KEY_KP2);
+    m.insert("kp3", keys::KEY_KP3);
+    m.insert("kp4", keys::KEY_KP4);
+    m.insert("kp5", keys::KEY_KP5);
+    m.insert("kp6", keys::KEY_KP6);
+    m.insert("kp7", keys::KEY_KP7);
+    m.insert("kp8", keys::KEY_KP8);
+    m.insert("kp9", keys::KEY_KP9);
+    m.insert("kpminus", keys::KEY_KPMINUS);
+    m.insert("kpplus", keys::KEY_KPPLUS);
+    m.insert("kpenter", keys::KEY_KPENTER);
+    m.insert("kpdot", keys::KEY_KPDOT);
+    m.insert("kpslash", keys::KEY_KPSLASH);
+    m.insert("kpasterisk", keys::KEY_KPASTERISK);
+
+    // Media and consumer keys
+    m.insert("mute", keys::KEY_MUTE);
+    m.insert("volumeup", keys::KEY_VOLUMEUP);
+    m.insert("volumedown", keys::KEY_VOLUMEDOWN);
+    m.insert("micmute", keys::KEY_MICMUTE);
+    m.insert("playpause", keys::KEY_PLAYPAUSE);
+    m.insert("play", keys::KEY_PLAY);
+    m.insert("stopcd", keys::KEY_STOPCD);
+    m.insert("nextsong", keys::KEY_NEXTSONG);
+    m.insert("prev", keys::KEY_PREVIOUSSONG);
+    m.insert("previoussong", keys::KEY_PREVIOUSSONG);
+    m.insert("brightnessup", keys::KEY_BRIGHTNESSUP);
+    m.insert("brightnessdown", keys::KEY_BRIGHTNESSDOWN);
+    m.insert("calc", keys::KEY_CALC);
+    m.insert("mail", keys::KEY_MAIL);
+    m.insert("homepage", keys::KEY_HOMEPAGE);
 
     // Punctuation
     m.insert("minus", keys::KEY_MINUS);
@@ -258,187 +315,422 @@ static KEY_MAP: LazyLock<HashMap<&'static str, u16>> = LazyLock::new(|| {
     m
 });
 
-/// Character to key mapping for typeout
-static CHAR_MAP: LazyLock<HashMap<char, (u16, bool)>> = LazyLock::new(|| {
-    let mut m = HashMap::new();
-    // Lowercase letters (no shift)
-    for (i, c) in ('a'..='z').enumerate() {
-        m.insert(c, (keys::KEY_A + i as u16, false));
+/// Key timing used when emitting keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    /// How long each key is held down
+    pub hold: Duration,
+    /// Delay between typed characters
+    pub key_delay: Duration,
+    /// Delay between chords of a sequence
+    pub chord_delay: Duration,
+    /// Maximum random extra delay added to each delay
+    pub jitter: Duration,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            hold: Duration::ZERO,
+            key_delay: Duration::from_millis(10),
+            chord_delay: Duration::from_millis(50),
+            jitter: Duration::ZERO,
+        }
     }
-    // Uppercase letters (with shift)
-    for (i, c) in ('A'..='Z').enumerate() {
-        m.insert(c, (keys::KEY_A + i as u16, true));
+}
+
+impl From<&Typing> for Timing {
+    fn from(typing: &Typing) -> Self {
+        let default = Self::default();
+        let ms = |v: Option<u64>, d: Duration| v.map(Duration::from_millis).unwrap_or(d);
+        Self {
+            hold: ms(typing.hold_ms, default.hold),
+            key_delay: ms(typing.key_delay_ms, default.key_delay),
+            chord_delay: ms(typing.chord_delay_ms, default.chord_delay),
+            jitter: ms(typing.jitter_ms, default.jitter),
+        }
     }
-    // Numbers
-    m.insert('0', (keys::KEY_0, false));
-    m.insert('1', (keys::KEY_1, false));
-    m.insert('2', (keys::KEY_2, false));
-    m.insert('3', (keys::KEY_3, false));
-    m.insert('4', (keys::KEY_4, false));
-    m.insert('5', (keys::KEY_5, false));
-    m.insert('6', (keys::KEY_6, false));
-    m.insert('7', (keys::KEY_7, false));
-    m.insert('8', (keys::KEY_8, false));
-    m.insert('9', (keys::KEY_9, false));
-    // Shifted number row
-    m.insert('!', (keys::KEY_1, true));
-    m.insert('@', (keys::KEY_2, true));
-    m.insert('#', (keys::KEY_3, true));
-    m.insert('$', (keys::KEY_4, true));
-    m.insert('%', (keys::KEY_5, true));
-    m.insert('^', (keys::KEY_6, true));
-    m.insert('&', (keys::KEY_7, true));
-    m.insert('*', (keys::KEY_8, true));
-    m.insert('(', (keys::KEY_9, true));
-    m.insert(')', (keys::KEY_0, true));
-    // Special characters
-    m.insert(' ', (keys::KEY_SPACE, false));
-    m.insert('\n', (keys::KEY_ENTER, false));
-    m.insert('\t', (keys::KEY_TAB, false));
-    m.insert('-', (keys::KEY_MINUS, false));
-    m.insert('_', (keys::KEY_MINUS, true));
-    m.insert('=', (keys::KEY_EQUAL, false));
-    m.insert('+', (keys::KEY_EQUAL, true));
-    m.insert('[', (keys::KEY_LEFTBRACE, false));
-    m.insert('{', (keys::KEY_LEFTBRACE, true));
-    m.insert(']', (keys::KEY_RIGHTBRACE, false));
-    m.insert('}', (keys::KEY_RIGHTBRACE, true));
-    m.insert(';', (keys::KEY_SEMICOLON, false));
-    m.insert(':', (keys::KEY_SEMICOLON, true));
-    m.insert('\'', (keys::KEY_APOSTROPHE, false));
-    m.insert('"', (keys::KEY_APOSTROPHE, true));
-    m.insert('`', (keys::KEY_GRAVE, false));
-    m.insert('~', (keys::KEY_GRAVE, true));
-    m.insert('\\', (keys::KEY_BACKSLASH, false));
-    m.insert('|', (keys::KEY_BACKSLASH, true));
-    m.insert(',', (keys::KEY_COMMA, false));
-    m.insert('<', (keys::KEY_COMMA, true));
-    m.insert('.', (keys::KEY_DOT, false));
-    m.insert('>', (keys::KEY_DOT, true));
-    m.insert('/', (keys::KEY_SLASH, false));
-    m.insert('?', (keys::KEY_SLASH, true));
-    m
-});
+}
 
-pub struct VirtualKeyboard {
-    file: File,
+/// Error returned when a running macro is cancelled
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Macro cancelled")
+    }
 }
 
-impl VirtualKeyboard {
-    /// Create a new virtual keyboard device
-    pub fn new() -> Result<Self> {
-        let file = OpenOptions::new()
-            .write(true)
-            .open("/dev/uinput")
-            .context("Failed to open /dev/uinput")?;
+impl std::error::Error for Cancelled {}
 
-        let fd = file.as_raw_fd();
+/// Granularity of cancellation checks during long waits
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
-        unsafe {
-            // Enable key events
-            if libc::ioctl(fd, UI_SET_EVBIT, EV_KEY as libc::c_int) < 0 {
-                anyhow::bail!("Failed to set EV_KEY");
-            }
+pub struct VirtualKeyboard {
+    /// Where key and pointer events go
+    backend: Box<dyn OutputBackend>,
+    /// Layout used to map characters to keys for typeout
+    layout: Layout,
+    /// Clipboard helper for the clipboard typeout fallback
+    clipboard: ClipboardConfig,
+    /// Key hold time and delays
+    timing: Timing,
+    /// xorshift state for delay jitter
+    rng: u64,
+    /// Set from another thread to abort the running macro
+    cancel: Arc<AtomicBool>,
+    /// Keys currently pressed, in press order
+    held: Vec<u16>,
+    /// Keep typed characters out of the logs
+    redact: bool,
+}
 
-            // Enable all key codes we might use
-            for key in 1..=keys::KEY_MAX {
-                libc::ioctl(fd, UI_SET_KEYBIT, key as libc::c_int);
-            }
+impl VirtualKeyboard {
+    /// Create the virtual keyboard and mouse devices
+    pub fn new() -> Result<Self> {
+        Ok(Self::with_backend(Box::new(UinputBackend::new()?)))
+    }
 
-            // Setup device info
-            let mut setup: UinputSetup = mem::zeroed();
-            setup.id.bustype = BUS_USB;
-            setup.id.vendor = 0x1234;
-            setup.id.product = 0x5678;
-            setup.id.version = 1;
-            let name = b"gkeys-rs virtual keyboard";
-            setup.name[..name.len()].copy_from_slice(name);
-
-            if libc::ioctl(fd, UI_DEV_SETUP, &setup) < 0 {
-                anyhow::bail!("Failed to setup uinput device");
-            }
+    /// Create a keyboard that logs events instead of writing them to uinput
+    pub fn dry_run() -> Self {
+        Self::with_backend(Box::new(DryRunBackend))
+    }
 
-            if libc::ioctl(fd, UI_DEV_CREATE) < 0 {
-                anyhow::bail!("Failed to create uinput device");
-            }
+    /// Create a keyboard sending events to `backend`
+    pub fn with_backend(backend: Box<dyn OutputBackend>) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Self {
+            backend,
+            layout: Layout::default(),
+            clipboard: ClipboardConfig::default(),
+            timing: Timing::default(),
+            rng: seed | 1,
+            cancel: Arc::new(AtomicBool::new(false)),
+            held: Vec::new(),
+            redact: false,
         }
+    }
 
-        // Give udev time to create the device node
-        std::thread::sleep(std::time::Duration::from_millis(100));
+    /// Set the keyboard layout used by typeout
+    pub fn set_layout(&mut self, layout: Layout) {
+        log::info!("Using keyboard layout '{}' for typeout", layout.name());
+        self.layout = layout;
+    }
 
-        Ok(Self { file })
+    /// Set the clipboard helper used by the clipboard typeout fallback
+    pub fn set_clipboard(&mut self, clipboard: ClipboardConfig) {
+        self.clipboard = clipboard;
     }
 
-    fn emit(&mut self, type_: u16, code: u16, value: i32) -> Result<()> {
-        let event = InputEvent {
-            time: libc::timeval {
-                tv_sec: 0,
-                tv_usec: 0,
-            },
-            type_,
-            code,
-            value,
-        };
+    /// Set key hold time and delays
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+    }
 
-        let bytes = unsafe {
-            std::slice::from_raw_parts(
-                &event as *const InputEvent as *const u8,
-                mem::size_of::<InputEvent>(),
-            )
-        };
+    /// Set the flag checked between keys to abort the running macro
+    pub fn set_cancel_flag(&mut self, cancel: Arc<AtomicBool>) {
+        self.cancel = cancel;
+    }
 
-        self.file.write_all(bytes)?;
+    /// The flag that cancels the running macro
+    pub fn cancel_flag(&self) -> Arc<AtomicBool> {
+        self.cancel.clone()
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        if self.cancel.load(Ordering::SeqCst) {
+            return Err(Cancelled.into());
+        }
         Ok(())
     }
 
-    fn sync(&mut self) -> Result<()> {
-        self.emit(EV_SYN, SYN_REPORT, 0)
+    /// Sleep in short slices so cancellation is noticed promptly
+    fn sleep(&self, duration: Duration) -> Result<()> {
+        if !self.backend.is_live() {
+            return self.check_cancelled();
+        }
+        let mut remaining = duration;
+        while !remaining.is_zero() {
+            self.check_cancelled()?;
+            let slice = remaining.min(CANCEL_POLL_INTERVAL);
+            std::thread::sleep(slice);
+            remaining -= slice;
+        }
+        self.check_cancelled()
+    }
+
+    /// Sleep for `base` plus a random jitter, failing with [`Cancelled`] if
+    /// the macro was cancelled
+    fn pause(&mut self, base: Duration) -> Result<()> {
+        let jitter_ms = self.timing.jitter.as_millis() as u64;
+        let extra = if jitter_ms > 0 {
+            // xorshift64
+            self.rng ^= self.rng << 13;
+            self.rng ^= self.rng >> 7;
+            self.rng ^= self.rng << 17;
+            Duration::from_millis(self.rng % (jitter_ms + 1))
+        } else {
+            Duration::ZERO
+        };
+        self.sleep(base + extra)
     }
 
-    /// Press a key (key down)
+    /// Press a key or mouse button (key down)
     pub fn press(&mut self, key: u16) -> Result<()> {
-        self.emit(EV_KEY, key, 1)?;
-        self.sync()
+        // Track before writing so a failed write still gets released later
+        if !self.held.contains(&key) {
+            self.held.push(key);
+        }
+        self.backend.emit(OutputEvent::Key(key, 1))
     }
 
-    /// Release a key (key up)
+    /// Release a key or mouse button (key up)
     pub fn release(&mut self, key: u16) -> Result<()> {
-        self.emit(EV_KEY, key, 0)?;
-        self.sync()
+        self.held.retain(|&k| k != key);
+        self.backend.emit(OutputEvent::Key(key, 0))
+    }
+
+    /// Move the pointer by a relative amount
+    pub fn move_pointer(&mut self, dx: i32, dy: i32) -> Result<()> {
+        self.backend.emit(OutputEvent::Move(dx, dy))
     }
 
-    /// Click a key (press and release)
+    /// Scroll by wheel clicks; positive `dy` scrolls up, positive `dx` right
+    pub fn scroll(&mut self, dx: i32, dy: i32) -> Result<()> {
+        if dx == 0 && dy == 0 {
+            return Ok(());
+        }
+        self.backend.emit(OutputEvent::Scroll(dx, dy))
+    }
+
+    /// Release every key still held, most recently pressed first
+    ///
+    /// Keeps going when a release fails so one bad write can't leave the
+    /// remaining keys stuck; each failure is logged and the first returned.
+    pub fn release_all(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for key in std::mem::take(&mut self.held).into_iter().rev() {
+            log::debug!("Releasing held key {}", key);
+            if let Err(e) = self.backend.emit(OutputEvent::Key(key, 0)) {
+                log::error!("Failed to release key {}: {:#}", key, e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    /// Whether any key is currently held down
+    pub fn has_held_keys(&self) -> bool {
+        !self.held.is_empty()
+    }
+
+    /// Click a key (press, hold, release)
     pub fn click(&mut self, key: u16) -> Result<()> {
         self.press(key)?;
-        self.release(key)
+        // Always release, even if cancelled while holding
+        let held = self.pause(self.timing.hold);
+        self.release(key)?;
+        held
     }
 
     /// Parse a key name to a key code
+    ///
+    /// Accepts the short names in `KEY_MAP` ("ctrl", "f5"), any `KEY_*` or
+    /// `BTN_*` name from input-event-codes.h with or without the `KEY_`
+    /// prefix ("KEY_VOLUMEUP", "micmute", "btn_left"), and decimal or hex
+    /// codes ("28", "0x1c"). Single digits are the number keys, not codes.
     pub fn parse_key(name: &str) -> Option<u16> {
-        let name = name.trim().to_lowercase();
-        // Handle KEY_XXX format
-        let name = name.strip_prefix("key_").unwrap_or(&name);
-        KEY_MAP.get(name).copied()
+        let lower = name.trim().to_lowercase();
+        let upper = lower.to_uppercase();
+        // Full kernel names always mean the kernel's key
+        if upper.starts_with("KEY_") || upper.starts_with("BTN_") {
+            if let Some(code) = keycodes::by_name(&upper) {
+                return Some(code);
+            }
+        }
+
+        let short = lower.strip_prefix("key_").unwrap_or(&lower);
+        if let Some(&code) = KEY_MAP.get(short) {
+            return Some(code);
+        }
+        if let Some(code) = keycodes::by_name(&format!("KEY_{}", upper)) {
+            return Some(code);
+        }
+
+        let code = match lower.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16).ok()?,
+            None => lower.parse::<u16>().ok()?,
+        };
+        (1..=keys::KEY_MAX).contains(&code).then_some(code)
     }
 
-    /// Type a string character by character
-    pub fn typeout(&mut self, text: &str) -> Result<()> {
+    /// Parse a mouse button name ("left", "right", "middle", "side", "extra"
+    /// or a `BTN_*` name) to a button code
+    pub fn parse_button(name: &str) -> Option<u16> {
+        match name.trim().to_lowercase().as_str() {
+            "left" => Some(keys::BTN_LEFT),
+            "right" => Some(keys::BTN_RIGHT),
+            "middle" => Some(keys::BTN_MIDDLE),
+            "side" | "back" => Some(keys::BTN_SIDE),
+            "extra" | "forward" => Some(keys::BTN_EXTRA),
+            other => Self::parse_key(other).filter(|code| MOUSE_BUTTONS.contains(code)),
+        }
+    }
+
+    /// Type a string character by character, using `fallback` for characters
+    /// the keyboard layout cannot produce
+    pub fn typeout(&mut self, text: &str, fallback: Fallback) -> Result<()> {
+        // Unmapped characters waiting to be pasted in one go
+        let mut pending = String::new();
+
         for c in text.chars() {
-            if let Some(&(key, shift)) = CHAR_MAP.get(&c) {
-                if shift {
-                    self.press(keys::KEY_LEFTSHIFT)?;
-                }
-                self.click(key)?;
-                if shift {
-                    self.release(keys::KEY_LEFTSHIFT)?;
+            match self.layout.lookup(c) {
+                Some(stroke) => {
+                    if !pending.is_empty() {
+                        self.paste_text(&pending)?;
+                        pending.clear();
+                    }
+                    self.type_stroke(stroke)?;
                 }
-                // Small delay between characters
-                std::thread::sleep(std::time::Duration::from_millis(10));
+                None => match fallback {
+                    Fallback::Skip => {
+                        log::warn!("No key for {} in layout, skipping", self.describe_char(c));
+                    }
+                    Fallback::Unicode => self.type_unicode(c)?,
+                    Fallback::Compose => self.type_compose(c)?,
+                    Fallback::Clipboard => pending.push(c),
+                },
             }
+            self.pause(self.timing.key_delay)?;
+        }
+
+        if !pending.is_empty() {
+            self.paste_text(&pending)?;
+        }
+        Ok(())
+    }
+
+    /// Type text without any of its characters appearing in the logs
+    pub fn typeout_redacted(&mut self, text: &str, fallback: Fallback) -> Result<()> {
+        self.redact = true;
+        let result = self.typeout(text, fallback);
+        self.redact = false;
+        result
+    }
+
+    /// Describe a character for logs, hiding it when redacting
+    fn describe_char(&self, c: char) -> String {
+        if self.redact {
+            "a character".to_string()
+        } else {
+            format!("{:?} (U+{:04X})", c, c as u32)
+        }
+    }
+
+    /// Press a key with the Shift/AltGr modifiers the layout requires
+    fn type_stroke(&mut self, stroke: KeyStroke) -> Result<()> {
+        if stroke.altgr {
+            self.press(keys::KEY_RIGHTALT)?;
+        }
+        if stroke.shift {
+            self.press(keys::KEY_LEFTSHIFT)?;
+        }
+        self.click(stroke.key)?;
+        if stroke.shift {
+            self.release(keys::KEY_LEFTSHIFT)?;
+        }
+        if stroke.altgr {
+            self.release(keys::KEY_RIGHTALT)?;
         }
         Ok(())
     }
 
+    /// Type characters that must exist in the layout (hex digits, compose input)
+    fn type_layout_chars(&mut self, chars: &str) -> Result<()> {
+        for c in chars.chars() {
+            let stroke = self
+                .layout
+                .lookup(c)
+                .with_context(|| format!("No key for {} in layout", self.describe_char(c)))?;
+            self.type_stroke(stroke)?;
+        }
+        Ok(())
+    }
+
+    /// Enter a character via GTK/IBus Unicode input (ctrl+shift+u, hex, space)
+    fn type_unicode(&mut self, c: char) -> Result<()> {
+        log::debug!("Typing {} via Unicode entry", self.describe_char(c));
+        self.shortcut("ctrl+shift+u")?;
+        self.type_layout_chars(&format!("{:x}", c as u32))?;
+        self.click(keys::KEY_SPACE)
+    }
+
+    /// Enter a character via a Compose key sequence
+    fn type_compose(&mut self, c: char) -> Result<()> {
+        let Some(sequence) = layout::compose_sequence(c) else {
+            log::warn!(
+                "No compose sequence for {}, skipping",
+                self.describe_char(c)
+            );
+            return Ok(());
+        };
+        log::debug!("Typing {} via compose sequence", self.describe_char(c));
+        self.click(keys::KEY_COMPOSE)?;
+        self.type_layout_chars(sequence)
+    }
+
+    /// Copy text with the clipboard helper, then send the paste shortcut
+    fn paste_text(&mut self, text: &str) -> Result<()> {
+        if !self.backend.is_live() {
+            log::info!(
+                "Dry run: not pasting {} characters via clipboard",
+                text.chars().count()
+            );
+            return Ok(());
+        }
+        log::debug!(
+            "Pasting {} characters via '{}'",
+            text.chars().count(),
+            self.clipboard.command
+        );
+
+        let mut child = Command::new("/bin/sh")
+            .arg("-c")
+            .arg(&self.clipboard.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| {
+                format!(
+                    "Failed to run clipboard helper '{}'",
+                    self.clipboard.command
+                )
+            })?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes())?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            anyhow::bail!(
+                "Clipboard helper '{}' failed: {}",
+                self.clipboard.command,
+                status
+            );
+        }
+
+        // Give the clipboard owner a moment before pasting
+        std::thread::sleep(Duration::from_millis(50));
+        let paste = self.clipboard.paste_actions.clone();
+        self.run_actions(&paste, Fallback::default())
+    }
+
     /// Execute a keyboard shortcut like "ctrl+shift+t"
     pub fn shortcut(&mut self, shortcut: &str) -> Result<()> {
         let parts: Vec<&str> = shortcut.split('+').map(|s| s.trim()).collect();
@@ -454,21 +746,58 @@ impl VirtualKeyboard {
             }
         }
 
+        let held = self.pause(self.timing.hold);
+
         // Release in reverse order
         for key in pressed.into_iter().rev() {
             self.release(key)?;
         }
 
-        Ok(())
+        held
     }
 
-    /// Execute a sequence of shortcuts like "ctrl+a, ctrl+c"
-    pub fn sequence(&mut self, seq: &str) -> Result<()> {
-        for part in seq.split(',') {
-            let part = part.trim();
-            if !part.is_empty() {
-                self.shortcut(part)?;
-                std::thread::sleep(std::time::Duration::from_millis(50));
+    /// Run compiled macro actions; `fallback` applies to text steps
+    pub fn run_actions(&mut self, actions: &[Action], fallback: Fallback) -> Result<()> {
+        for action in actions {
+            match action {
+                Action::Press(key) => {
+                    self.press(*key)?;
+                    self.pause(self.timing.key_delay)?;
+                }
+                Action::Release(key) => {
+                    self.release(*key)?;
+                    self.pause(self.timing.key_delay)?;
+                }
+                Action::Tap(key) => {
+                    self.click(*key)?;
+                    self.pause(self.timing.key_delay)?;
+                }
+                Action::Chord(chord) => {
+                    for &key in chord {
+                        self.press(key)?;
+                    }
+                    let held = self.pause(self.timing.hold);
+                    for &key in chord.iter().rev() {
+                        self.release(key)?;
+                    }
+                    held?;
+                    self.pause(self.timing.chord_delay)?;
+                }
+                Action::Text(text) => self.typeout(text, fallback)?,
+                Action::Move(dx, dy) => {
+                    self.move_pointer(*dx, *dy)?;
+                    self.pause(self.timing.key_delay)?;
+                }
+                Action::Scroll(dx, dy) => {
+                    self.scroll(*dx, *dy)?;
+                    self.pause(self.timing.key_delay)?;
+                }
+                Action::Wait(duration) => self.sleep(*duration)?,
+                Action::Repeat(times, inner) => {
+                    for _ in 0..*times {
+                        self.run_actions(inner, fallback)?;
+                    }
+                }
             }
         }
         Ok(())
@@ -477,8 +806,186 @@ impl VirtualKeyboard {
 
 impl Drop for VirtualKeyboard {
     fn drop(&mut self) {
-        unsafe {
-            libc::ioctl(self.file.as_raw_fd(), UI_DEV_DESTROY);
+        // Keys still down when the device disappears stay stuck in the
+        // compositor; the backend destroys the devices after this
+        if let Err(e) = self.release_all() {
+            log::error!("Failed to release held keys on shutdown: {:#}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{self, RecordingBackend};
+    use crate::steps;
+
+    fn recording_keyboard() -> (VirtualKeyboard, RecordingBackend) {
+        let backend = RecordingBackend::default();
+        let keyboard = VirtualKeyboard::with_backend(Box::new(backend.clone()));
+        (keyboard, backend)
+    }
+
+    fn down(key: u16) -> OutputEvent {
+        OutputEvent::Key(key, 1)
+    }
+
+    fn up(key: u16) -> OutputEvent {
+        OutputEvent::Key(key, 0)
+    }
+
+    #[test]
+    fn test_typeout_events() {
+        let (mut keyboard, backend) = recording_keyboard();
+        keyboard.typeout("aB!", Fallback::Skip).unwrap();
+        assert_eq!(
+            backend.events(),
+            vec![
+                down(keys::KEY_A),
+                up(keys::KEY_A),
+                down(keys::KEY_LEFTSHIFT),
+                down(keys::KEY_B),
+                up(keys::KEY_B),
+                up(keys::KEY_LEFTSHIFT),
+                down(keys::KEY_LEFTSHIFT),
+                down(keys::KEY_1),
+                up(keys::KEY_1),
+                up(keys::KEY_LEFTSHIFT),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shortcut_events() {
+        let (mut keyboard, backend) = recording_keyboard();
+        let actions = steps::compile_shortcut("ctrl+shift+t").unwrap();
+        keyboard.run_actions(&actions, Fallback::Skip).unwrap();
+        assert_eq!(
+            backend.events(),
+            vec![
+                down(keys::KEY_LEFTCTRL),
+                down(keys::KEY_LEFTSHIFT),
+                down(keys::KEY_T),
+                up(keys::KEY_T),
+                up(keys::KEY_LEFTSHIFT),
+                up(keys::KEY_LEFTCTRL),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sequence_events() {
+        let (mut keyboard, backend) = recording_keyboard();
+        let actions = steps::compile_sequence("ctrl+a, ctrl+c, enter").unwrap();
+        keyboard.run_actions(&actions, Fallback::Skip).unwrap();
+        assert_eq!(
+            backend.events(),
+            vec![
+                down(keys::KEY_LEFTCTRL),
+                down(keys::KEY_A),
+                up(keys::KEY_A),
+                up(keys::KEY_LEFTCTRL),
+                down(keys::KEY_LEFTCTRL),
+                down(keys::KEY_C),
+                up(keys::KEY_C),
+                up(keys::KEY_LEFTCTRL),
+                down(keys::KEY_ENTER),
+                up(keys::KEY_ENTER),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mouse_events() {
+        let (mut keyboard, backend) = recording_keyboard();
+        let actions = vec![
+            Action::Move(10, -5),
+            Action::Tap(keys::BTN_LEFT),
+            Action::Scroll(0, 0),
+            Action::Scroll(0, -3),
+        ];
+        keyboard.run_actions(&actions, Fallback::Skip).unwrap();
+        let events = backend.events();
+        assert_eq!(
+            events,
+            vec![
+                OutputEvent::Move(10, -5),
+                down(keys::BTN_LEFT),
+                up(keys::BTN_LEFT),
+                OutputEvent::Scroll(0, -3),
+            ]
+        );
+        assert!(events.iter().all(OutputEvent::is_mouse));
+    }
+
+    #[test]
+    fn test_release_all_after_cancel() {
+        let (mut keyboard, backend) = recording_keyboard();
+        let cancel = Arc::new(AtomicBool::new(false));
+        keyboard.set_cancel_flag(cancel.clone());
+
+        keyboard.press(keys::KEY_LEFTCTRL).unwrap();
+        keyboard.press(keys::KEY_LEFTSHIFT).unwrap();
+        cancel.store(true, Ordering::SeqCst);
+        let err = keyboard
+            .run_actions(&[Action::Wait(Duration::from_secs(1))], Fallback::Skip)
+            .unwrap_err();
+        assert!(err.is::<Cancelled>());
+        assert!(keyboard.has_held_keys());
+
+        keyboard.release_all().unwrap();
+        assert!(!keyboard.has_held_keys());
+        assert_eq!(
+            backend.events()[2..],
+            [up(keys::KEY_LEFTSHIFT), up(keys::KEY_LEFTCTRL)]
+        );
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let parse = VirtualKeyboard::parse_key;
+        assert_eq!(parse("ctrl"), Some(keys::KEY_LEFTCTRL));
+        assert_eq!(parse("KEY_F5"), Some(keys::KEY_F5));
+        assert_eq!(parse("KEY_F24"), Some(194));
+        assert_eq!(parse("KEY_MICMUTE"), Some(248));
+        assert_eq!(parse("volumeup"), Some(115));
+        assert_eq!(parse("BTN_LEFT"), Some(0x110));
+        assert_eq!(parse("KEY_SCREENLOCK"), Some(152));
+        assert_eq!(parse("28"), Some(keys::KEY_ENTER));
+        assert_eq!(parse("0x1c"), Some(keys::KEY_ENTER));
+        assert_eq!(parse("1"), Some(keys::KEY_1));
+        assert_eq!(parse("0"), Some(keys::KEY_0));
+        assert_eq!(parse("0x0"), None);
+        assert_eq!(parse("70000"), None);
+        assert_eq!(parse("KEY_FOOBAR"), None);
+    }
+
+    #[test]
+    fn test_media_keys() {
+        let enabled: Vec<u16> = output::device_keys().collect();
+        for (name, code) in [
+            ("volumeup", keys::KEY_VOLUMEUP),
+            ("volumedown", keys::KEY_VOLUMEDOWN),
+            ("mute", keys::KEY_MUTE),
+            ("micmute", keys::KEY_MICMUTE),
+            ("playpause", keys::KEY_PLAYPAUSE),
+            ("nextsong", keys::KEY_NEXTSONG),
+            ("prev", keys::KEY_PREVIOUSSONG),
+            ("KEY_PLAYPAUSE", keys::KEY_PLAYPAUSE),
+            // Kernel names keep their kernel codes
+            ("KEY_STOP", 128),
+            ("KEY_NEXT", 407),
+            ("KEY_PREVIOUS", 412),
+        ] {
+            assert_eq!(VirtualKeyboard::parse_key(name), Some(code), "{}", name);
+            assert!(enabled.contains(&code), "{} not enabled", name);
+        }
+        for (name, &code) in KEY_MAP.iter() {
+            // Every named key must be emittable
+            assert!(enabled.contains(&code), "{} not enabled", name);
+            // Short names never hide a kernel name with another code
+            let kernel = keycodes::by_name(&format!("KEY_{}", name.to_uppercase()));
+            assert!(kernel.is_none_or(|kernel| kernel == code), "{}", name);
         }
     }
 }